@@ -0,0 +1,99 @@
+use crate::channel_mapper;
+use crate::pipeline::CHANNELS;
+use cpal::Sample;
+use std::io::{Seek, Write};
+use std::path::Path;
+
+// Reads a whole WAV file as interleaved stereo f32 samples in [-1.0, 1.0], at the
+// file's own sample rate
+pub fn read_stereo(path: &Path) -> Result<(Vec<f32>, hound::WavSpec), anyhow::Error> {
+    let mut reader = hound::WavReader::open(path)
+        .map_err(|err| anyhow::Error::msg(format!("Can't read {}: {err}", path.display())))?;
+    let spec = reader.spec();
+    check_format(&spec)?;
+    let samples = read_samples(&mut reader)?;
+    let samples = channel_mapper::to_stereo(&samples, spec.channels as usize);
+    debug_assert_eq!(samples.len() % CHANNELS, 0);
+    Ok((samples, spec))
+}
+
+// Writes f32 samples in [-1.0, 1.0] in whichever format the writer was created
+// with, clipping anything out of range for integer formats
+pub fn write_samples<W: Write + Seek>(
+    writer: &mut hound::WavWriter<W>,
+    samples: &[f32],
+) -> Result<(), anyhow::Error> {
+    let spec = writer.spec();
+    check_format(&spec)?;
+    for &sample in samples {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Float, _) => writer.write_sample(sample)?,
+            (hound::SampleFormat::Int, 8) => writer.write_sample(sample.to_sample::<i8>())?,
+            (hound::SampleFormat::Int, 16) => writer.write_sample(sample.to_sample::<i16>())?,
+            (hound::SampleFormat::Int, 24) => {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * I24_MAX) as i32)?
+            }
+            _ => writer.write_sample(sample.to_sample::<i32>())?,
+        }
+    }
+    Ok(())
+}
+
+// 32-bit float stereo, which keeps everything the pipeline produces
+pub fn float_spec(sample_rate: u32) -> hound::WavSpec {
+    hound::WavSpec {
+        channels: CHANNELS as _,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    }
+}
+
+// The WAV spec for recording what a device plays, at `sample_rate`. Device formats
+// WAV has no room for are recorded in the closest one it does.
+pub fn spec_for_device(format: cpal::SampleFormat, sample_rate: u32) -> hound::WavSpec {
+    let (sample_format, bits_per_sample) = match format {
+        cpal::SampleFormat::I8 | cpal::SampleFormat::U8 => (hound::SampleFormat::Int, 8),
+        cpal::SampleFormat::I16 | cpal::SampleFormat::U16 => (hound::SampleFormat::Int, 16),
+        format if format.is_float() => (hound::SampleFormat::Float, 32),
+        _ => (hound::SampleFormat::Int, 32),
+    };
+    hound::WavSpec {
+        channels: CHANNELS as _,
+        sample_rate,
+        bits_per_sample,
+        sample_format,
+    }
+}
+
+const I24_MAX: f32 = ((1 << 23) - 1) as f32;
+
+// Only 8, 16, 24 and 32-bit integer and 32-bit float samples are supported
+fn check_format(spec: &hound::WavSpec) -> Result<(), anyhow::Error> {
+    if spec.sample_rate == 0 {
+        return Err(anyhow::Error::msg("WAV sample rate can't be 0 Hz"));
+    }
+    match (spec.sample_format, spec.bits_per_sample) {
+        (hound::SampleFormat::Float, 32) | (hound::SampleFormat::Int, 8 | 16 | 24 | 32) => Ok(()),
+        (format, bits) => Err(anyhow::Error::msg(format!(
+            "Unsupported WAV sample format: {bits}-bit {format:?}"
+        ))),
+    }
+}
+
+fn read_samples<R: std::io::Read>(
+    reader: &mut hound::WavReader<R>,
+) -> Result<Vec<f32>, anyhow::Error> {
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok(samples)
+}
@@ -0,0 +1,314 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+use std::str::FromStr;
+
+// A value that changes over the course of a run, evaluated at a time in seconds
+// from the start
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "shape", rename_all = "kebab-case")]
+pub enum Automation {
+    // Straight lines between (seconds, value) points, holding the first and last
+    // values before and after them
+    Breakpoints {
+        points: Vec<(f64, f64)>,
+    },
+    // Repeating wave between `min` and `max`
+    Lfo {
+        waveform: Waveform,
+        rate_hz: f64,
+        min: f64,
+        max: f64,
+        // Where in the cycle the wave starts, from 0.0 to 1.0
+        #[serde(default)]
+        phase: f64,
+    },
+    // Wanders randomly between `min` and `max`, starting halfway, moving on average
+    // `step` per second
+    RandomWalk {
+        step: f64,
+        min: f64,
+        max: f64,
+        #[serde(skip)]
+        state: Option<WalkState>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WalkState {
+    value: f64,
+    time_s: f64,
+}
+
+impl Automation {
+    // Random walks draw from `rng`, so they follow the simulator's seed
+    pub fn value_at<R: Rng>(&mut self, time_s: f64, rng: &mut R) -> f64 {
+        match self {
+            Automation::Breakpoints { points } => breakpoint_value(points, time_s),
+            Automation::Lfo {
+                waveform,
+                rate_hz,
+                min,
+                max,
+                phase,
+            } => {
+                let position = (time_s * *rate_hz + *phase).rem_euclid(1.0);
+                *min + (*max - *min) * waveform.unipolar(position)
+            }
+            Automation::RandomWalk {
+                step,
+                min,
+                max,
+                state,
+            } => {
+                let state = state.get_or_insert(WalkState {
+                    value: (*min + *max) / 2.0,
+                    time_s,
+                });
+                let elapsed = (time_s - state.time_s).max(0.0);
+                if elapsed > 0.0 {
+                    // Scaled by the square root of time like Brownian motion, so
+                    // the wander doesn't depend on how often it's evaluated
+                    let change = rng.gen_range(-1.0..=1.0) * *step * elapsed.sqrt();
+                    state.value = reflect(state.value + change, *min, *max);
+                    state.time_s = time_s;
+                }
+                state.value
+            }
+        }
+    }
+
+    // The same automation with every value multiplied by `factor`, for unit changes
+    pub fn scaled(self, factor: f64) -> Self {
+        match self {
+            Automation::Breakpoints { points } => Automation::Breakpoints {
+                points: points
+                    .into_iter()
+                    .map(|(time_s, value)| (time_s, value * factor))
+                    .collect(),
+            },
+            Automation::Lfo {
+                waveform,
+                rate_hz,
+                min,
+                max,
+                phase,
+            } => Automation::Lfo {
+                waveform,
+                rate_hz,
+                min: min * factor,
+                max: max * factor,
+                phase,
+            },
+            Automation::RandomWalk { step, min, max, .. } => Automation::RandomWalk {
+                step: step * factor,
+                min: min * factor,
+                max: max * factor,
+                state: None,
+            },
+        }
+    }
+}
+
+// Command line form of an automation:
+//   0:0,30:0.4,60:0          breakpoints as seconds:value pairs
+//   lfo:sine:0.1:0:0.4       waveform, rate in Hz, min and max
+//   walk:0.05:0:0.3          step per second, min and max
+impl FromStr for Automation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse_number = |number: &str| {
+            number
+                .trim()
+                .parse::<f64>()
+                .map_err(|err| format!("'{number}': {err}"))
+        };
+        let fields: Vec<&str> = value.split(':').collect();
+
+        match fields.as_slice() {
+            ["lfo", waveform, rate_hz, min, max] => Ok(Automation::Lfo {
+                waveform: waveform.parse()?,
+                rate_hz: parse_number(rate_hz)?,
+                min: parse_number(min)?,
+                max: parse_number(max)?,
+                phase: 0.0,
+            }),
+            ["walk", step, min, max] => Ok(Automation::RandomWalk {
+                step: parse_number(step)?,
+                min: parse_number(min)?,
+                max: parse_number(max)?,
+                state: None,
+            }),
+            ["lfo", ..] => Err("expected lfo:<waveform>:<rate hz>:<min>:<max>".to_string()),
+            ["walk", ..] => Err("expected walk:<step>:<min>:<max>".to_string()),
+            _ => {
+                let mut points = value
+                    .split(',')
+                    .map(|point| match point.split_once(':') {
+                        Some((time_s, value)) => Ok((parse_number(time_s)?, parse_number(value)?)),
+                        None => Err(format!("expected seconds:value, got '{point}'")),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Ok(Automation::Breakpoints { points })
+            }
+        }
+    }
+}
+
+impl Waveform {
+    // Value from 0.0 to 1.0 at `position` through the cycle
+    pub fn unipolar(self, position: f64) -> f64 {
+        match self {
+            Waveform::Sine => 0.5 - 0.5 * (position * TAU).cos(),
+            Waveform::Triangle => 1.0 - (2.0 * position - 1.0).abs(),
+            Waveform::Square => {
+                if position < 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Waveform::Saw => position,
+        }
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "square" => Ok(Waveform::Square),
+            "saw" => Ok(Waveform::Saw),
+            _ => Err(format!(
+                "unknown waveform '{value}', expected sine, triangle, square or saw"
+            )),
+        }
+    }
+}
+
+fn breakpoint_value(points: &[(f64, f64)], time_s: f64) -> f64 {
+    let next = points.partition_point(|&(point_s, _)| point_s <= time_s);
+    match (
+        next.checked_sub(1).map(|index| points[index]),
+        points.get(next),
+    ) {
+        (None, None) => 0.0,
+        (Some((_, value)), None) | (None, Some(&(_, value))) => value,
+        (Some((start_s, start)), Some(&(end_s, end))) => {
+            start + (end - start) * (time_s - start_s) / (end_s - start_s)
+        }
+    }
+}
+
+// Bounces a value that stepped past either end back inside the range
+fn reflect(value: f64, min: f64, max: f64) -> f64 {
+    if value < min {
+        (2.0 * min - value).min(max)
+    } else if value > max {
+        (2.0 * max - value).max(min)
+    } else {
+        value
+    }
+}
+
+// Automation for each of the network's conditions, all optional. Latency and
+// jitter are in microseconds like the rest of the network settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkAutomation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loss: Option<Automation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_us: Option<Automation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter_us: Option<Automation>,
+}
+
+impl NetworkAutomation {
+    pub fn is_empty(&self) -> bool {
+        self.loss.is_none() && self.latency_us.is_none() && self.jitter_us.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn breakpoints_interpolate_and_hold() {
+        let mut automation: Automation = "20:0.4,0:0,40:0".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_close(automation.value_at(-1.0, &mut rng), 0.0);
+        assert_close(automation.value_at(10.0, &mut rng), 0.2);
+        assert_close(automation.value_at(20.0, &mut rng), 0.4);
+        assert_close(automation.value_at(35.0, &mut rng), 0.1);
+        assert_close(automation.value_at(100.0, &mut rng), 0.0);
+    }
+
+    #[test]
+    fn lfo_follows_the_waveform() {
+        let mut rng = StdRng::seed_from_u64(7);
+        // One cycle every 4 seconds between 10 and 50
+        let mut sine: Automation = "lfo:sine:0.25:10:50".parse().unwrap();
+        assert_close(sine.value_at(0.0, &mut rng), 10.0);
+        assert_close(sine.value_at(1.0, &mut rng), 30.0);
+        assert_close(sine.value_at(2.0, &mut rng), 50.0);
+        assert_close(sine.value_at(4.0, &mut rng), 10.0);
+
+        let mut saw: Automation = "lfo:saw:0.25:10:50".parse().unwrap();
+        assert_close(saw.value_at(1.0, &mut rng), 20.0);
+        assert_close(saw.value_at(5.0, &mut rng), 20.0);
+
+        let mut square: Automation = "lfo:square:0.25:10:50".parse().unwrap();
+        assert_close(square.value_at(1.0, &mut rng), 50.0);
+        assert_close(square.value_at(3.0, &mut rng), 10.0);
+    }
+
+    #[test]
+    fn random_walk_starts_halfway_and_stays_in_range() {
+        let mut walk: Automation = "walk:20:10:120".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_close(walk.value_at(0.0, &mut rng), 65.0);
+
+        let mut moved = false;
+        for step in 1..1000 {
+            let value = walk.value_at(step as f64 * 0.1, &mut rng);
+            assert!((10.0..=120.0).contains(&value), "{value} out of range");
+            moved |= value != 65.0;
+        }
+        assert!(moved);
+        // Asking again for the same time doesn't move it
+        let value = walk.value_at(99.9, &mut rng);
+        assert_close(walk.value_at(99.9, &mut rng), value);
+    }
+
+    #[test]
+    fn rejects_malformed_automation() {
+        assert!("lfo:sine:0.2".parse::<Automation>().is_err());
+        assert!("lfo:wobble:0.2:0:1".parse::<Automation>().is_err());
+        assert!("walk:1:2".parse::<Automation>().is_err());
+        assert!("0:0,20".parse::<Automation>().is_err());
+    }
+}
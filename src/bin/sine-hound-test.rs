@@ -1,6 +1,4 @@
-use hound;
 use std::f32::consts::PI;
-use std::i16;
 
 const WAVE_SPEC: hound::WavSpec = hound::WavSpec {
     channels: 2,
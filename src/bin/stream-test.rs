@@ -1,11 +1,30 @@
+use clap::Parser;
 use console::Term;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Data, FromSample, Sample, SampleFormat, SampleRate};
+use cpal::traits::DeviceTrait;
+use cpal::{FromSample, Sample, SampleFormat};
+use rust_opus_test::devices::{self, DeviceSelector, Direction};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{f32::consts::PI, thread};
 
+// Plays a sine wave on an output device, tuned with the keyboard
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Audio API to use, e.g. alsa, jack or coreaudio [default: the platform's default]
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Output device to play through, by its index in --list-devices, part of its name, or "default"
+    #[arg(long, default_value = "default")]
+    device: DeviceSelector,
+
+    /// List every host and device with the configs they support, then exit
+    #[arg(long)]
+    list_devices: bool,
+}
+
 fn main() {
     // Create the frequency as an Arc<AtomicU32> in main
     let frequency = Arc::new(AtomicU32::new(440.0f32.to_bits()));
@@ -14,55 +33,27 @@ fn main() {
     let keyboard_frequency = frequency.clone();
 
     // Spawn keyboard handling in a separate thread with the frequency
-    let keyboard_thread = thread::spawn(move || {
+    thread::spawn(move || {
         handle_keyboard_input(keyboard_frequency);
     });
 
-    let host = cpal::default_host();
-
-    // Return all available host devices with the device method
-    let devices = host.devices().unwrap();
-
-    println!("All available devices:");
-    for device in devices {
-        println!("{}", device.name().unwrap());
+    let args = Args::parse();
+    if args.list_devices {
+        devices::list();
+        return;
     }
 
-    // Return all available input devices using the input devices method
-    let input_devices = host.input_devices().unwrap();
-
-    println!("\nAll available input devices:");
-    for device in input_devices {
-        println!("{}", device.name().unwrap());
-    }
-
-    // Set our output device to the default
-    let device = host
-        .default_output_device()
+    let host = devices::host(args.host.as_deref()).expect("no such audio host");
+    let device = devices::select(&host, Direction::Output, &args.device)
         .expect("no output device available");
 
     // Print the output device's name....
-    println!("\nDefault output device: {}", device.name().unwrap());
-
-    // Get a fresh iterator and get the first config
-    let mut supported_configs_range = device
-        .supported_output_configs()
-        .expect("error while querying configs");
-
-    // Set desired sample rate and try to get the supported configration with that sample rate
-    let desired_sample_rate = SampleRate(48000);
-    let config_range = supported_configs_range
-        .find(|range| {
-            range.min_sample_rate() <= desired_sample_rate
-                && range.max_sample_rate() >= desired_sample_rate
-        })
-        .expect(&format!(
-            "no supported config found for sample rate {:?}Hz",
-            desired_sample_rate,
-        ));
-    let supported_config = config_range
-        .try_with_sample_rate(desired_sample_rate)
-        .expect("48000 Hz is not supported");
+    println!("\nOutput device: {}", devices::device_name(&device));
+
+    // Stereo at 48 kHz if the device can, otherwise its highest rate
+    let supported_config =
+        devices::stereo_output_config(&device).expect("no supported output config");
+    let sample_rate = supported_config.sample_rate().0 as f32;
 
     // Print supported configuration
     println!("\nSupported configuration: {:?}", supported_config);
@@ -76,22 +67,22 @@ fn main() {
 
     // Match statement for different sample formats
     let write_frequency = frequency.clone();
-    let stream = match sample_format {
+    let _stream = match sample_format {
         SampleFormat::F32 => device.build_output_stream(
             &config,
-            move |data, info| write_sine::<f32>(data, info, &write_frequency),
+            move |data, info| write_sine::<f32>(data, info, &write_frequency, sample_rate),
             err_fn,
             None,
         ),
         SampleFormat::I16 => device.build_output_stream(
             &config,
-            move |data, info| write_sine::<i16>(data, info, &write_frequency),
+            move |data, info| write_sine::<i16>(data, info, &write_frequency, sample_rate),
             err_fn,
             None,
         ),
         SampleFormat::U16 => device.build_output_stream(
             &config,
-            move |data, info| write_sine::<u16>(data, info, &write_frequency),
+            move |data, info| write_sine::<u16>(data, info, &write_frequency, sample_rate),
             err_fn,
             None,
         ),
@@ -105,10 +96,10 @@ fn write_sine<T: Sample + FromSample<f32>>(
     data: &mut [T],
     _: &cpal::OutputCallbackInfo,
     frequency: &Arc<AtomicU32>,
+    sample_rate: f32,
 ) {
     static mut SAMPLE_CLOCK: f32 = 0.0;
     let current_freq = f32::from_bits(frequency.load(Ordering::Relaxed));
-    let sample_rate = 48000.0;
     let volume = 0.5;
 
     for sample in data.iter_mut() {
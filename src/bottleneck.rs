@@ -0,0 +1,367 @@
+use crate::network_simulator::Packet;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// IPv4, UDP and RTP headers carried on top of every Opus payload
+const DEFAULT_OVERHEAD_BYTES: usize = 40;
+
+// A congested link: packets queue up in front of a link of limited rate and are
+// sent one at a time, each taking as long as its size needs. When Opus sends more
+// than the link carries, the queue grows, delay climbs and packets get dropped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bottleneck {
+    pub rate_bps: u64,
+    // Room for packets waiting for the link, not counting the one being sent
+    pub queue_bytes: usize,
+    #[serde(default)]
+    pub discipline: QueueDiscipline,
+    // Optional token bucket in front of the link, e.g. a provider's rate limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shaper: Option<TokenBucket>,
+    #[serde(default = "default_overhead_bytes")]
+    pub overhead_bytes: usize,
+    #[serde(skip)]
+    state: QueueState,
+}
+
+// How the queue decides what to drop
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "discipline", rename_all = "kebab-case")]
+pub enum QueueDiscipline {
+    // Drop arriving packets once the queue is full
+    #[default]
+    DropTail,
+    // Random early detection: start dropping arriving packets at random as the
+    // average queue grows, before it's actually full
+    Red {
+        min_threshold_bytes: usize,
+        max_threshold_bytes: usize,
+        max_probability: f64,
+    },
+    // Controlled delay: drop packets at the head once they've been kept waiting
+    // longer than `target_us` for a whole `interval_us`, more often the longer that lasts
+    Codel {
+        target_us: u64,
+        interval_us: u64,
+    },
+}
+
+// Lets traffic through at `rate_bps` on average, with bursts of up to
+// `burst_bytes` at once after a quiet spell
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TokenBucket {
+    pub rate_bps: u64,
+    pub burst_bytes: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct QueueState {
+    queue: VecDeque<QueuedPacket>,
+    queued_bytes: usize,
+    // When the link finishes sending what it's on
+    link_free_us: u64,
+    // Token bucket fill level at `tokens_updated_us`
+    tokens: f64,
+    tokens_updated_us: u64,
+    // Weighted average of the queue size, for RED
+    average_queue_bytes: f64,
+    // CoDel's control state
+    above_target_since_us: Option<u64>,
+    dropping: bool,
+    drop_count: u32,
+    next_drop_us: u64,
+    dropped: u64,
+}
+
+#[derive(Debug, Clone)]
+struct QueuedPacket {
+    packet: Packet,
+    size: usize,
+    enqueued_us: u64,
+}
+
+fn default_overhead_bytes() -> usize {
+    DEFAULT_OVERHEAD_BYTES
+}
+
+impl Bottleneck {
+    pub fn new(rate_bps: u64, queue_bytes: usize) -> Self {
+        Self {
+            rate_bps,
+            queue_bytes,
+            discipline: QueueDiscipline::DropTail,
+            shaper: None,
+            overhead_bytes: DEFAULT_OVERHEAD_BYTES,
+            state: QueueState::default(),
+        }
+    }
+
+    pub fn with_discipline(mut self, discipline: QueueDiscipline) -> Self {
+        self.discipline = discipline;
+        self
+    }
+
+    // Packets dropped by the queue so far
+    pub fn dropped(&self) -> u64 {
+        self.state.dropped
+    }
+
+    // Queues a packet arriving at `now_us`, or drops it
+    pub fn enqueue<R: Rng>(&mut self, packet: Packet, now_us: u64, rng: &mut R) {
+        let size = packet.payload.len() + self.overhead_bytes;
+        let state = &mut self.state;
+
+        if state.queued_bytes + size > self.queue_bytes {
+            state.dropped += 1;
+            return;
+        }
+        if let QueueDiscipline::Red {
+            min_threshold_bytes,
+            max_threshold_bytes,
+            max_probability,
+        } = self.discipline
+        {
+            const WEIGHT: f64 = 0.2;
+            state.average_queue_bytes =
+                (1.0 - WEIGHT) * state.average_queue_bytes + WEIGHT * state.queued_bytes as f64;
+            let average = state.average_queue_bytes;
+            let drop_probability = if average < min_threshold_bytes as f64 {
+                0.0
+            } else if average >= max_threshold_bytes as f64 {
+                1.0
+            } else {
+                max_probability * (average - min_threshold_bytes as f64)
+                    / (max_threshold_bytes - min_threshold_bytes) as f64
+            };
+            if rng.gen::<f64>() < drop_probability {
+                state.dropped += 1;
+                return;
+            }
+        }
+
+        state.queued_bytes += size;
+        state.queue.push_back(QueuedPacket {
+            packet,
+            size,
+            enqueued_us: now_us,
+        });
+    }
+
+    // Sends everything the link gets to by `now_us`, returning the packets with
+    // `arrival_us` set to when they finished leaving the link
+    pub fn dequeue_until(&mut self, now_us: u64) -> Vec<Packet> {
+        let mut sent = Vec::new();
+        while let Some(head) = self.state.queue.front() {
+            let start_us = self
+                .state
+                .link_free_us
+                .max(head.enqueued_us)
+                .max(self.tokens_ready_us(head.size, head.enqueued_us));
+            if start_us > now_us {
+                break;
+            }
+
+            let QueuedPacket {
+                mut packet,
+                size,
+                enqueued_us,
+            } = self.state.queue.pop_front().expect("queue has a head");
+            self.state.queued_bytes -= size;
+            if self.codel_should_drop(start_us, start_us - enqueued_us) {
+                self.state.dropped += 1;
+                continue;
+            }
+
+            self.take_tokens(size, start_us);
+            // Serialization delay of this packet on the link
+            let transmit_us = (size as u64 * 8 * 1_000_000).div_ceil(self.rate_bps.max(1));
+            self.state.link_free_us = start_us + transmit_us;
+            packet.arrival_us = self.state.link_free_us;
+            sent.push(packet);
+        }
+        sent
+    }
+
+    // Earliest time from `after_us` that the shaper has enough tokens for `size` bytes
+    fn tokens_ready_us(&self, size: usize, after_us: u64) -> u64 {
+        let Some(shaper) = self.shaper else {
+            return after_us;
+        };
+        let needed = size.min(shaper.burst_bytes) as f64;
+        let from_us = after_us.max(self.state.tokens_updated_us);
+        let tokens = self.tokens_at(shaper, from_us);
+        if tokens >= needed {
+            return from_us;
+        }
+        let bytes_per_us = shaper.rate_bps as f64 / 8_000_000.0;
+        from_us + ((needed - tokens) / bytes_per_us).ceil() as u64
+    }
+
+    fn tokens_at(&self, shaper: TokenBucket, time_us: u64) -> f64 {
+        let elapsed_us = time_us.saturating_sub(self.state.tokens_updated_us) as f64;
+        (self.state.tokens + elapsed_us * shaper.rate_bps as f64 / 8_000_000.0)
+            .min(shaper.burst_bytes as f64)
+    }
+
+    fn take_tokens(&mut self, size: usize, time_us: u64) {
+        if let Some(shaper) = self.shaper {
+            let tokens = self.tokens_at(shaper, time_us);
+            self.state.tokens = (tokens - size.min(shaper.burst_bytes) as f64).max(0.0);
+            self.state.tokens_updated_us = time_us;
+        }
+    }
+
+    // CoDel's dequeue decision, after RFC 8289
+    fn codel_should_drop(&mut self, now_us: u64, sojourn_us: u64) -> bool {
+        let QueueDiscipline::Codel {
+            target_us,
+            interval_us,
+        } = self.discipline
+        else {
+            return false;
+        };
+        let state = &mut self.state;
+
+        if sojourn_us < target_us || state.queued_bytes == 0 {
+            state.above_target_since_us = None;
+            state.dropping = false;
+            return false;
+        }
+        let above_since_us = *state.above_target_since_us.get_or_insert(now_us);
+
+        if state.dropping {
+            if now_us >= state.next_drop_us {
+                state.drop_count += 1;
+                state.next_drop_us = now_us + control_law(interval_us, state.drop_count);
+                return true;
+            }
+            false
+        } else if now_us - above_since_us >= interval_us {
+            state.dropping = true;
+            state.drop_count = 1;
+            state.next_drop_us = now_us + control_law(interval_us, state.drop_count);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Time until CoDel's next drop, shrinking with the square root of drops so far
+fn control_law(interval_us: u64, drop_count: u32) -> u64 {
+    (interval_us as f64 / (drop_count as f64).sqrt()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // 60 bytes of payload, 100 on the wire with the default overhead
+    fn packet(sequence: u64) -> Packet {
+        Packet {
+            sequence,
+            sent_at_us: 0,
+            arrival_us: 0,
+            payload: vec![0; 60],
+        }
+    }
+
+    // Sends a packet every `interval_us` for `duration_us`, draining the link as it
+    // goes, and returns the arrival times of what got through
+    fn run(bottleneck: &mut Bottleneck, interval_us: u64, duration_us: u64) -> Vec<u64> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut arrivals = Vec::new();
+        for (sequence, now_us) in (0..duration_us).step_by(interval_us as usize).enumerate() {
+            arrivals.extend(
+                bottleneck
+                    .dequeue_until(now_us)
+                    .iter()
+                    .map(|p| p.arrival_us),
+            );
+            bottleneck.enqueue(packet(sequence as u64), now_us, &mut rng);
+        }
+        arrivals.extend(
+            bottleneck
+                .dequeue_until(u64::MAX)
+                .iter()
+                .map(|p| p.arrival_us),
+        );
+        arrivals
+    }
+
+    #[test]
+    fn drop_tail_drops_once_the_queue_is_full() {
+        // 100 bytes take 100 ms at 8 kbps
+        let mut bottleneck = Bottleneck::new(8_000, 300);
+        let mut rng = StdRng::seed_from_u64(1);
+        for sequence in 0..5 {
+            bottleneck.enqueue(packet(sequence), 0, &mut rng);
+        }
+        assert_eq!(bottleneck.dropped(), 2);
+        let arrivals: Vec<u64> = bottleneck
+            .dequeue_until(u64::MAX)
+            .iter()
+            .map(|packet| packet.arrival_us)
+            .collect();
+        assert_eq!(arrivals, vec![100_000, 200_000, 300_000]);
+    }
+
+    #[test]
+    fn red_drops_before_the_queue_is_full() {
+        let mut bottleneck =
+            Bottleneck::new(8_000, 100_000).with_discipline(QueueDiscipline::Red {
+                min_threshold_bytes: 500,
+                max_threshold_bytes: 1500,
+                max_probability: 0.5,
+            });
+        let mut rng = StdRng::seed_from_u64(1);
+        for sequence in 0..100 {
+            bottleneck.enqueue(packet(sequence), 0, &mut rng);
+        }
+        // Nothing is dropped until the average passes the lower threshold, and
+        // everything once it passes the upper one, long before the queue fills
+        let kept = 100 - bottleneck.dropped() as usize;
+        assert!(bottleneck.dropped() > 0);
+        assert!((6..=25).contains(&kept), "kept {kept}");
+    }
+
+    #[test]
+    fn codel_drops_when_packets_wait_too_long() {
+        let codel = QueueDiscipline::Codel {
+            target_us: 5_000,
+            interval_us: 100_000,
+        };
+        // 100 bytes every 5 ms on a link that takes 10 ms each, with room for all
+        let mut tail = Bottleneck::new(80_000, 1_000_000);
+        let mut codel = Bottleneck::new(80_000, 1_000_000).with_discipline(codel);
+        let tail_arrivals = run(&mut tail, 5_000, 2_000_000);
+        let codel_arrivals = run(&mut codel, 5_000, 2_000_000);
+
+        assert_eq!(tail.dropped(), 0);
+        assert!(codel.dropped() > 0);
+        assert_eq!(codel_arrivals.len() + codel.dropped() as usize, 400);
+        // Dropping at the head keeps the queue, and so the delay, from growing as far
+        assert!(codel_arrivals.last() < tail_arrivals.last());
+    }
+
+    #[test]
+    fn token_bucket_limits_the_rate() {
+        let mut bottleneck = Bottleneck::new(10_000_000, 100_000);
+        bottleneck.shaper = Some(TokenBucket {
+            rate_bps: 80_000,
+            burst_bytes: 100,
+        });
+        let arrivals = run(&mut bottleneck, 1_000, 100_000);
+        assert_eq!(arrivals.len(), 100);
+        // 10 kB/s lets one 100 byte packet through every 10 ms, each then taking
+        // 80 us to cross the fast link
+        let last_us = *arrivals.last().unwrap();
+        assert!(
+            (990_000..=1_000_080).contains(&last_us),
+            "last at {last_us}"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+// Where each channel of a multichannel file ends up in a stereo mix, as
+// (left gain, right gain). Follows the default WAV channel order.
+fn downmix_gains(channels: usize) -> Vec<(f32, f32)> {
+    const LEFT: (f32, f32) = (1.0, 0.0);
+    const RIGHT: (f32, f32) = (0.0, 1.0);
+    const CENTRE: (f32, f32) = (FRAC_1_SQRT_2, FRAC_1_SQRT_2);
+    const LFE: (f32, f32) = (0.0, 0.0);
+    const SURROUND_LEFT: (f32, f32) = (FRAC_1_SQRT_2, 0.0);
+    const SURROUND_RIGHT: (f32, f32) = (0.0, FRAC_1_SQRT_2);
+    const BACK_CENTRE: (f32, f32) = (0.5, 0.5);
+
+    match channels {
+        1 => vec![(1.0, 1.0)],
+        2 => vec![LEFT, RIGHT],
+        3 => vec![LEFT, RIGHT, CENTRE],
+        4 => vec![LEFT, RIGHT, SURROUND_LEFT, SURROUND_RIGHT],
+        5 => vec![LEFT, RIGHT, CENTRE, SURROUND_LEFT, SURROUND_RIGHT],
+        6 => vec![LEFT, RIGHT, CENTRE, LFE, SURROUND_LEFT, SURROUND_RIGHT],
+        7 => vec![
+            LEFT,
+            RIGHT,
+            CENTRE,
+            LFE,
+            BACK_CENTRE,
+            SURROUND_LEFT,
+            SURROUND_RIGHT,
+        ],
+        8 => vec![
+            LEFT,
+            RIGHT,
+            CENTRE,
+            LFE,
+            SURROUND_LEFT,
+            SURROUND_RIGHT,
+            SURROUND_LEFT,
+            SURROUND_RIGHT,
+        ],
+        // No standard layout, so alternate channels between the two sides
+        _ => (0..channels)
+            .map(|channel| if channel % 2 == 0 { LEFT } else { RIGHT })
+            .collect(),
+    }
+}
+
+// Maps interleaved audio with any number of channels to interleaved stereo. Mono
+// is copied to both sides and surround layouts are folded down ITU style, without
+// normalising, so a loud surround mix can exceed full scale.
+pub fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+    if channels == 2 {
+        return samples.to_vec();
+    }
+
+    let gains = downmix_gains(channels);
+    let mut stereo = Vec::with_capacity(samples.len() / channels * 2);
+    for frame in samples.chunks_exact(channels) {
+        let (left, right) = frame.iter().zip(gains.iter()).fold(
+            (0.0, 0.0),
+            |(left, right), (&sample, &(left_gain, right_gain))| {
+                (left + sample * left_gain, right + sample * right_gain)
+            },
+        );
+        stereo.push(left);
+        stereo.push(right);
+    }
+    stereo
+}
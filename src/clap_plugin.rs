@@ -0,0 +1,772 @@
+// The glitch effect as a CLAP plugin. Built into the cdylib with
+// `--features clap-plugin`, which then only needs renaming to a .clap bundle.
+use crate::automation::Waveform;
+use crate::clap_abi::*;
+use crate::codec::FrameDuration;
+use crate::glitch_effect::{GlitchEffect, GlitchSettings, Transport};
+use crate::loss_strategy::LossStrategy;
+use std::ffi::{c_char, c_void, CStr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const PLUGIN_ID: &CStr = c"com.github.nzwart.rust-opus.glitch";
+
+// Parameter ids, which are also their positions in `PARAMS`. Saved state and host
+// automation refer to them, so they can't be renumbered.
+const LOSS: u32 = 0;
+const BURST_LENGTH: u32 = 1;
+const LATENCY: u32 = 2;
+const JITTER: u32 = 3;
+const BITRATE: u32 = 4;
+const FRAME_SIZE: u32 = 5;
+const BUFFER: u32 = 6;
+const SYNC: u32 = 7;
+const SYNC_SHAPE: u32 = 8;
+const LOSS_FILL: u32 = 9;
+const MIX: u32 = 10;
+
+const FRAME_SIZE_LABELS: [&str; 9] = [
+    "2.5 ms", "5 ms", "10 ms", "20 ms", "40 ms", "60 ms", "80 ms", "100 ms", "120 ms",
+];
+const SYNC_LABELS: [&str; 7] = ["Off", "1/1", "1/2", "1/4", "1/8", "1/16", "1/32"];
+// Quarter notes per cycle for each of `SYNC_LABELS`
+const SYNC_BEATS: [f64; 7] = [0.0, 4.0, 2.0, 1.0, 0.5, 0.25, 0.125];
+const SYNC_SHAPE_LABELS: [&str; 4] = ["Sine", "Triangle", "Square", "Saw"];
+const SYNC_SHAPES: [Waveform; 4] = [
+    Waveform::Sine,
+    Waveform::Triangle,
+    Waveform::Square,
+    Waveform::Saw,
+];
+const LOSS_FILL_LABELS: [&str; 6] = [
+    "Opus PLC",
+    "Silence",
+    "Repeat",
+    "Hold",
+    "Reverse",
+    "Crossfade",
+];
+const LOSS_FILLS: [LossStrategy; 6] = [
+    LossStrategy::Plc,
+    LossStrategy::Silence,
+    LossStrategy::Repeat { times: 2 },
+    LossStrategy::Hold { frames: 1 },
+    LossStrategy::Reverse,
+    LossStrategy::Crossfade,
+];
+
+// How a parameter's value is shown to the user
+enum Display {
+    Percent,
+    Packets,
+    Milliseconds,
+    Kbps,
+    Choice(&'static [&'static str]),
+}
+
+struct ParamSpec {
+    name: &'static str,
+    min: f64,
+    max: f64,
+    default: f64,
+    display: Display,
+    // Frame size and buffer depth change the latency, so they only take effect
+    // when the host restarts the plugin and aren't offered for automation
+    automatable: bool,
+}
+
+const PARAMS: [ParamSpec; 11] = [
+    ParamSpec {
+        name: "Loss",
+        min: 0.0,
+        max: 1.0,
+        default: 0.1,
+        display: Display::Percent,
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Burst length",
+        min: 1.0,
+        max: 20.0,
+        default: 2.0,
+        display: Display::Packets,
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Latency",
+        min: 0.0,
+        max: 500.0,
+        default: 20.0,
+        display: Display::Milliseconds,
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Jitter",
+        min: 0.0,
+        max: 200.0,
+        default: 10.0,
+        display: Display::Milliseconds,
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Bitrate",
+        min: 500.0,
+        max: 256_000.0,
+        default: 32_000.0,
+        display: Display::Kbps,
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Frame size",
+        min: 0.0,
+        max: 8.0,
+        default: 3.0,
+        display: Display::Choice(&FRAME_SIZE_LABELS),
+        automatable: false,
+    },
+    ParamSpec {
+        name: "Buffer",
+        min: 0.0,
+        max: 1000.0,
+        default: 60.0,
+        display: Display::Milliseconds,
+        automatable: false,
+    },
+    ParamSpec {
+        name: "Loss sync",
+        min: 0.0,
+        max: 6.0,
+        default: 0.0,
+        display: Display::Choice(&SYNC_LABELS),
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Sync shape",
+        min: 0.0,
+        max: 3.0,
+        default: 0.0,
+        display: Display::Choice(&SYNC_SHAPE_LABELS),
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Loss fill",
+        min: 0.0,
+        max: 5.0,
+        default: 0.0,
+        display: Display::Choice(&LOSS_FILL_LABELS),
+        automatable: true,
+    },
+    ParamSpec {
+        name: "Dry/Wet",
+        min: 0.0,
+        max: 1.0,
+        default: 1.0,
+        display: Display::Percent,
+        automatable: true,
+    },
+];
+
+impl ParamSpec {
+    fn clamp(&self, value: f64) -> f64 {
+        let value = value.clamp(self.min, self.max);
+        match self.display {
+            Display::Choice(_) => value.round(),
+            _ => value,
+        }
+    }
+
+    fn format(&self, value: f64) -> String {
+        match self.display {
+            Display::Percent => format!("{:.1} %", value * 100.0),
+            Display::Packets => format!("{value:.1} packets"),
+            Display::Milliseconds => format!("{value:.0} ms"),
+            Display::Kbps => format!("{:.1} kbps", value / 1000.0),
+            Display::Choice(labels) => labels
+                .get(value.round() as usize)
+                .unwrap_or(&"?")
+                .to_string(),
+        }
+    }
+
+    fn parse(&self, text: &str) -> Option<f64> {
+        let text = text.trim();
+        if let Display::Choice(labels) = self.display {
+            return labels
+                .iter()
+                .position(|label| label.eq_ignore_ascii_case(text))
+                .map(|index| index as f64);
+        }
+        // Whatever unit follows the number is ignored
+        let number_len = text
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+            .unwrap_or(text.len());
+        let number: f64 = text[..number_len].parse().ok()?;
+        Some(match self.display {
+            Display::Percent => number / 100.0,
+            Display::Kbps => number * 1000.0,
+            _ => number,
+        })
+    }
+}
+
+// One instance of the plugin, reached through `clap_plugin::plugin_data`
+struct GlitchPlugin {
+    host: *const clap_host,
+    // Parameter values as f64 bits, shared between the main and audio threads
+    values: [AtomicU64; PARAMS.len()],
+    effect: Mutex<Option<GlitchEffect>>,
+    sample_rate: AtomicU32,
+    // Largest block the host will process, which the effect makes room for up front
+    max_frames: AtomicU32,
+    latency: AtomicU32,
+    restart_requested: AtomicBool,
+}
+
+impl GlitchPlugin {
+    fn value(&self, id: u32) -> f64 {
+        f64::from_bits(self.values[id as usize].load(Ordering::Relaxed))
+    }
+
+    fn set_value(&self, id: u32, value: f64) {
+        if let Some(spec) = PARAMS.get(id as usize) {
+            self.values[id as usize].store(spec.clamp(value).to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    fn settings(&self) -> GlitchSettings {
+        let sync = self.value(SYNC) as usize;
+        GlitchSettings {
+            loss: self.value(LOSS) as f32,
+            burst_length: self.value(BURST_LENGTH) as f32,
+            latency_ms: self.value(LATENCY),
+            jitter_ms: self.value(JITTER),
+            bitrate: self.value(BITRATE) as i32,
+            frame_ms: FrameDuration::ALL[self.value(FRAME_SIZE) as usize],
+            buffer_ms: self.value(BUFFER),
+            sync_beats: (sync > 0).then(|| SYNC_BEATS[sync]),
+            sync_shape: SYNC_SHAPES[self.value(SYNC_SHAPE) as usize],
+            loss_strategy: LOSS_FILLS[self.value(LOSS_FILL) as usize],
+            wet: self.value(MIX) as f32,
+        }
+    }
+
+    fn build_effect(&self) -> Option<GlitchEffect> {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        match GlitchEffect::new(self.settings(), sample_rate) {
+            Ok(mut effect) => {
+                // Nothing on the audio thread should have to allocate
+                effect.reserve_block(self.max_frames.load(Ordering::Relaxed) as usize);
+                Some(effect)
+            }
+            Err(err) => {
+                eprintln!("Can't start the glitch effect: {err}");
+                None
+            }
+        }
+    }
+
+    // Takes parameter changes from the host. They apply from the start of the
+    // block rather than at the exact sample.
+    unsafe fn apply_events(&self, events: *const clap_input_events) {
+        if events.is_null() {
+            return;
+        }
+        for index in 0..((*events).size)(events) {
+            let header = ((*events).get)(events, index);
+            if header.is_null()
+                || (*header).space_id != CLAP_CORE_EVENT_SPACE_ID
+                || (*header).type_ != CLAP_EVENT_PARAM_VALUE
+            {
+                continue;
+            }
+            let event = &*(header as *const clap_event_param_value);
+            self.set_value(event.param_id, event.value);
+        }
+    }
+
+    // Asks the host to restart the plugin so new latency settings take effect
+    unsafe fn request_restart(&self) {
+        if !self.restart_requested.swap(true, Ordering::Relaxed) {
+            ((*self.host).request_restart)(self.host);
+        }
+    }
+}
+
+unsafe fn instance<'a>(plugin: *const clap_plugin) -> &'a GlitchPlugin {
+    &*((*plugin).plugin_data as *const GlitchPlugin)
+}
+
+// Statics holding C strings need to be shared between threads, which raw
+// pointers can't be by default. They only ever point at other statics.
+#[repr(transparent)]
+struct Shared<T>(T);
+unsafe impl<T> Sync for Shared<T> {}
+
+static FEATURES: Shared<[*const c_char; 4]> = Shared([
+    c"audio-effect".as_ptr(),
+    c"glitch".as_ptr(),
+    c"stereo".as_ptr(),
+    std::ptr::null(),
+]);
+
+static DESCRIPTOR: Shared<clap_plugin_descriptor> = Shared(clap_plugin_descriptor {
+    clap_version: CLAP_VERSION,
+    id: PLUGIN_ID.as_ptr(),
+    name: c"Opus Glitch".as_ptr(),
+    vendor: c"rust-opus".as_ptr(),
+    url: c"https://github.com/nzwart/rust-opus".as_ptr(),
+    manual_url: c"".as_ptr(),
+    support_url: c"".as_ptr(),
+    version: match CStr::from_bytes_with_nul(concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes()) {
+        Ok(version) => version.as_ptr(),
+        Err(_) => panic!("version isn't a C string"),
+    },
+    description: c"Opus encoding through a lossy, jittery simulated network".as_ptr(),
+    features: &FEATURES.0 as *const _ as *const *const c_char,
+});
+
+#[no_mangle]
+pub static clap_entry: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: entry_init,
+    deinit: entry_deinit,
+    get_factory: entry_get_factory,
+};
+
+static FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: factory_get_plugin_count,
+    get_plugin_descriptor: factory_get_plugin_descriptor,
+    create_plugin: factory_create_plugin,
+};
+
+static AUDIO_PORTS: clap_plugin_audio_ports = clap_plugin_audio_ports {
+    count: audio_ports_count,
+    get: audio_ports_get,
+};
+
+static PARAMS_EXTENSION: clap_plugin_params = clap_plugin_params {
+    count: params_count,
+    get_info: params_get_info,
+    get_value: params_get_value,
+    value_to_text: params_value_to_text,
+    text_to_value: params_text_to_value,
+    flush: params_flush,
+};
+
+static LATENCY_EXTENSION: clap_plugin_latency = clap_plugin_latency { get: latency_get };
+
+static STATE_EXTENSION: clap_plugin_state = clap_plugin_state {
+    save: state_save,
+    load: state_load,
+};
+
+unsafe extern "C" fn entry_init(_plugin_path: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn entry_deinit() {}
+
+unsafe extern "C" fn entry_get_factory(factory_id: *const c_char) -> *const c_void {
+    if is_id(factory_id, CLAP_PLUGIN_FACTORY_ID) {
+        &FACTORY as *const _ as *const c_void
+    } else {
+        std::ptr::null()
+    }
+}
+
+unsafe extern "C" fn factory_get_plugin_count(_factory: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn factory_get_plugin_descriptor(
+    _factory: *const clap_plugin_factory,
+    index: u32,
+) -> *const clap_plugin_descriptor {
+    if index == 0 {
+        &DESCRIPTOR.0
+    } else {
+        std::ptr::null()
+    }
+}
+
+unsafe extern "C" fn factory_create_plugin(
+    _factory: *const clap_plugin_factory,
+    host: *const clap_host,
+    plugin_id: *const c_char,
+) -> *const clap_plugin {
+    if host.is_null() || !is_id(plugin_id, PLUGIN_ID) {
+        return std::ptr::null();
+    }
+    let state = Box::new(GlitchPlugin {
+        host,
+        values: PARAMS
+            .each_ref()
+            .map(|spec| AtomicU64::new(spec.default.to_bits())),
+        effect: Mutex::new(None),
+        sample_rate: AtomicU32::new(0),
+        max_frames: AtomicU32::new(0),
+        latency: AtomicU32::new(0),
+        restart_requested: AtomicBool::new(false),
+    });
+    let plugin = Box::new(clap_plugin {
+        desc: &DESCRIPTOR.0,
+        plugin_data: Box::into_raw(state) as *mut c_void,
+        init: plugin_init,
+        destroy: plugin_destroy,
+        activate: plugin_activate,
+        deactivate: plugin_deactivate,
+        start_processing: plugin_start_processing,
+        stop_processing: plugin_stop_processing,
+        reset: plugin_reset,
+        process: plugin_process,
+        get_extension: plugin_get_extension,
+        on_main_thread: plugin_on_main_thread,
+    });
+    Box::into_raw(plugin)
+}
+
+unsafe extern "C" fn plugin_init(_plugin: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_destroy(plugin: *const clap_plugin) {
+    let plugin = Box::from_raw(plugin as *mut clap_plugin);
+    drop(Box::from_raw(plugin.plugin_data as *mut GlitchPlugin));
+}
+
+unsafe extern "C" fn plugin_activate(
+    plugin: *const clap_plugin,
+    sample_rate: f64,
+    _min_frames_count: u32,
+    max_frames_count: u32,
+) -> bool {
+    let state = instance(plugin);
+    state
+        .sample_rate
+        .store(sample_rate.round() as u32, Ordering::Relaxed);
+    state.max_frames.store(max_frames_count, Ordering::Relaxed);
+    let Some(effect) = state.build_effect() else {
+        return false;
+    };
+
+    let latency = effect.latency_frames();
+    if state.latency.swap(latency, Ordering::Relaxed) != latency {
+        let host = state.host;
+        let host_latency =
+            ((*host).get_extension)(host, CLAP_EXT_LATENCY.as_ptr()) as *const clap_host_latency;
+        if !host_latency.is_null() {
+            ((*host_latency).changed)(host);
+        }
+    }
+    state.restart_requested.store(false, Ordering::Relaxed);
+    *state.effect.lock().unwrap() = Some(effect);
+    true
+}
+
+unsafe extern "C" fn plugin_deactivate(plugin: *const clap_plugin) {
+    *instance(plugin).effect.lock().unwrap() = None;
+}
+
+unsafe extern "C" fn plugin_start_processing(_plugin: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_stop_processing(_plugin: *const clap_plugin) {}
+
+unsafe extern "C" fn plugin_reset(plugin: *const clap_plugin) {
+    let state = instance(plugin);
+    let mut effect = state.effect.lock().unwrap();
+    if effect.is_some() {
+        *effect = state.build_effect();
+    }
+}
+
+unsafe extern "C" fn plugin_process(
+    plugin: *const clap_plugin,
+    process: *const clap_process,
+) -> i32 {
+    let state = instance(plugin);
+    let process = &*process;
+    state.apply_events(process.in_events);
+
+    // Only ever contended while the host is (de)activating, when it shouldn't be
+    // processing anyway
+    let Ok(mut effect) = state.effect.try_lock() else {
+        return CLAP_PROCESS_CONTINUE;
+    };
+    let Some(effect) = effect.as_mut() else {
+        return CLAP_PROCESS_ERROR;
+    };
+    let settings = state.settings();
+    if effect.needs_rebuild(&settings) {
+        state.request_restart();
+    }
+    if effect.update(&settings).is_err() {
+        return CLAP_PROCESS_ERROR;
+    }
+
+    // Nothing to write to, so nothing to do
+    if process.audio_outputs_count < 1 || process.audio_outputs.is_null() {
+        return CLAP_PROCESS_CONTINUE;
+    }
+    // Only 32-bit buffers are supported
+    let output = &*process.audio_outputs;
+    if output.channel_count < 2 || output.data32.is_null() {
+        return CLAP_PROCESS_ERROR;
+    }
+    let frames = process.frames_count as usize;
+    let mut channels = [std::ptr::null_mut::<f32>(); 2];
+    for (channel, output_channel) in channels.iter_mut().enumerate() {
+        *output_channel = *output.data32.add(channel);
+        if output_channel.is_null() {
+            return CLAP_PROCESS_ERROR;
+        }
+    }
+
+    // Without usable input the effect runs on silence, so its timing carries on
+    let input = (process.audio_inputs_count >= 1 && !process.audio_inputs.is_null())
+        .then(|| &*process.audio_inputs)
+        .filter(|input| input.channel_count >= 2 && !input.data32.is_null());
+    for (channel, &output_channel) in channels.iter().enumerate() {
+        let input_channel = match input {
+            Some(input) => *input.data32.add(channel),
+            None => std::ptr::null_mut(),
+        };
+        if input_channel.is_null() {
+            std::ptr::write_bytes(output_channel, 0, frames);
+        } else if input_channel != output_channel {
+            // Hosts may hand over the same buffer for input and output
+            std::ptr::copy_nonoverlapping(input_channel, output_channel, frames);
+        }
+    }
+    let left = std::slice::from_raw_parts_mut(channels[0], frames);
+    let right = std::slice::from_raw_parts_mut(channels[1], frames);
+
+    match effect.process(left, right, transport(process.transport)) {
+        Ok(()) => CLAP_PROCESS_CONTINUE,
+        Err(_) => CLAP_PROCESS_ERROR,
+    }
+}
+
+unsafe extern "C" fn plugin_get_extension(
+    _plugin: *const clap_plugin,
+    id: *const c_char,
+) -> *const c_void {
+    if is_id(id, CLAP_EXT_AUDIO_PORTS) {
+        &AUDIO_PORTS as *const _ as *const c_void
+    } else if is_id(id, CLAP_EXT_PARAMS) {
+        &PARAMS_EXTENSION as *const _ as *const c_void
+    } else if is_id(id, CLAP_EXT_LATENCY) {
+        &LATENCY_EXTENSION as *const _ as *const c_void
+    } else if is_id(id, CLAP_EXT_STATE) {
+        &STATE_EXTENSION as *const _ as *const c_void
+    } else {
+        std::ptr::null()
+    }
+}
+
+unsafe extern "C" fn plugin_on_main_thread(_plugin: *const clap_plugin) {}
+
+// One stereo input and one stereo output, which can share buffers
+unsafe extern "C" fn audio_ports_count(_plugin: *const clap_plugin, _is_input: bool) -> u32 {
+    1
+}
+
+unsafe extern "C" fn audio_ports_get(
+    _plugin: *const clap_plugin,
+    index: u32,
+    _is_input: bool,
+    info: *mut clap_audio_port_info,
+) -> bool {
+    if index != 0 || info.is_null() {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = 0;
+    write_str(&mut info.name, "Main");
+    info.flags = CLAP_AUDIO_PORT_IS_MAIN;
+    info.channel_count = 2;
+    info.port_type = CLAP_PORT_STEREO.as_ptr();
+    info.in_place_pair = 0;
+    true
+}
+
+unsafe extern "C" fn params_count(_plugin: *const clap_plugin) -> u32 {
+    PARAMS.len() as u32
+}
+
+unsafe extern "C" fn params_get_info(
+    _plugin: *const clap_plugin,
+    param_index: u32,
+    param_info: *mut clap_param_info,
+) -> bool {
+    let Some(spec) = PARAMS.get(param_index as usize) else {
+        return false;
+    };
+    if param_info.is_null() {
+        return false;
+    }
+    let info = &mut *param_info;
+    info.id = param_index;
+    info.flags = match spec.display {
+        Display::Choice(_) => CLAP_PARAM_IS_STEPPED | CLAP_PARAM_IS_ENUM,
+        _ => 0,
+    };
+    if spec.automatable {
+        info.flags |= CLAP_PARAM_IS_AUTOMATABLE;
+    }
+    info.cookie = std::ptr::null_mut();
+    write_str(&mut info.name, spec.name);
+    write_str(&mut info.module, "");
+    info.min_value = spec.min;
+    info.max_value = spec.max;
+    info.default_value = spec.default;
+    true
+}
+
+unsafe extern "C" fn params_get_value(
+    plugin: *const clap_plugin,
+    param_id: u32,
+    value: *mut f64,
+) -> bool {
+    if param_id as usize >= PARAMS.len() || value.is_null() {
+        return false;
+    }
+    *value = instance(plugin).value(param_id);
+    true
+}
+
+unsafe extern "C" fn params_value_to_text(
+    _plugin: *const clap_plugin,
+    param_id: u32,
+    value: f64,
+    display: *mut c_char,
+    size: u32,
+) -> bool {
+    let Some(spec) = PARAMS.get(param_id as usize) else {
+        return false;
+    };
+    if display.is_null() || size == 0 {
+        return false;
+    }
+    let buffer = std::slice::from_raw_parts_mut(display, size as usize);
+    write_str(buffer, &spec.format(value));
+    true
+}
+
+unsafe extern "C" fn params_text_to_value(
+    _plugin: *const clap_plugin,
+    param_id: u32,
+    display: *const c_char,
+    value: *mut f64,
+) -> bool {
+    let Some(spec) = PARAMS.get(param_id as usize) else {
+        return false;
+    };
+    if display.is_null() || value.is_null() {
+        return false;
+    }
+    let Ok(text) = CStr::from_ptr(display).to_str() else {
+        return false;
+    };
+    match spec.parse(text) {
+        Some(parsed) => {
+            *value = spec.clamp(parsed);
+            true
+        }
+        None => false,
+    }
+}
+
+unsafe extern "C" fn params_flush(
+    plugin: *const clap_plugin,
+    in_events: *const clap_input_events,
+    _out_events: *const clap_output_events,
+) {
+    instance(plugin).apply_events(in_events);
+}
+
+unsafe extern "C" fn latency_get(plugin: *const clap_plugin) -> u32 {
+    instance(plugin).latency.load(Ordering::Relaxed)
+}
+
+// State is every parameter as a little-endian id and f64 value, so parameters
+// added later simply keep their defaults when loading older state
+unsafe extern "C" fn state_save(plugin: *const clap_plugin, stream: *const clap_ostream) -> bool {
+    let state = instance(plugin);
+    let mut bytes = Vec::new();
+    for id in 0..PARAMS.len() as u32 {
+        bytes.extend_from_slice(&id.to_le_bytes());
+        bytes.extend_from_slice(&state.value(id).to_le_bytes());
+    }
+
+    let mut written = 0;
+    while written < bytes.len() {
+        let remaining = &bytes[written..];
+        let result = ((*stream).write)(
+            stream,
+            remaining.as_ptr() as *const c_void,
+            remaining.len() as u64,
+        );
+        if result <= 0 {
+            return false;
+        }
+        written += result as usize;
+    }
+    true
+}
+
+unsafe extern "C" fn state_load(plugin: *const clap_plugin, stream: *const clap_istream) -> bool {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 256];
+    loop {
+        let result = ((*stream).read)(
+            stream,
+            chunk.as_mut_ptr() as *mut c_void,
+            chunk.len() as u64,
+        );
+        match result {
+            0 => break,
+            len if len > 0 => bytes.extend_from_slice(&chunk[..len as usize]),
+            _ => return false,
+        }
+    }
+    if bytes.len() % 12 != 0 {
+        return false;
+    }
+
+    let state = instance(plugin);
+    for entry in bytes.chunks_exact(12) {
+        let id = u32::from_le_bytes(entry[..4].try_into().unwrap());
+        let value = f64::from_le_bytes(entry[4..].try_into().unwrap());
+        state.set_value(id, value);
+    }
+    true
+}
+
+unsafe fn transport(transport: *const clap_event_transport) -> Transport {
+    if transport.is_null() {
+        return Transport::default();
+    }
+    let transport = &*transport;
+    Transport {
+        tempo_bpm: (transport.flags & CLAP_TRANSPORT_HAS_TEMPO != 0).then_some(transport.tempo),
+        beats: (transport.flags & CLAP_TRANSPORT_HAS_BEATS_TIMELINE != 0)
+            .then(|| transport.song_pos_beats as f64 / CLAP_BEATTIME_FACTOR),
+    }
+}
+
+unsafe fn is_id(id: *const c_char, expected: &CStr) -> bool {
+    !id.is_null() && CStr::from_ptr(id) == expected
+}
+
+// Copies `text` into a fixed-size C string, cutting it short if it doesn't fit
+fn write_str(buffer: &mut [c_char], text: &str) {
+    let len = text.len().min(buffer.len().saturating_sub(1));
+    for (dest, &byte) in buffer.iter_mut().zip(&text.as_bytes()[..len]) {
+        *dest = byte as c_char;
+    }
+    if let Some(end) = buffer.get_mut(len) {
+        *end = 0;
+    }
+}
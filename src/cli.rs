@@ -0,0 +1,474 @@
+use clap::Parser;
+use rust_opus_test::automation::Automation;
+use rust_opus_test::bottleneck::{Bottleneck, QueueDiscipline};
+use rust_opus_test::codec::{
+    check_sample_rate, ApplicationMode, FrameDuration, MaxBandwidth, Signal, VbrMode,
+};
+use rust_opus_test::devices::DeviceSelector;
+use rust_opus_test::jitter_buffer::JitterBufferMode;
+use rust_opus_test::loss_model::{GilbertElliott, LossModel};
+use rust_opus_test::loss_strategy::LossStrategy;
+use rust_opus_test::mixer::LossEffect;
+use rust_opus_test::preset::Preset;
+use rust_opus_test::step_sequencer::{Lane, StepSequencer};
+use std::path::PathBuf;
+
+// Network and codec options all default to the chosen preset, and only override
+// it when given explicitly
+#[derive(Parser, Debug)]
+#[command(about = "Plays audio through Opus and a simulated bad network connection")]
+pub struct Args {
+    /// WAV file to process
+    #[arg(short, long, default_value = "input.wav")]
+    pub input: PathBuf,
+
+    /// Where to write the processed audio
+    #[arg(short, long, default_value = "output_recording.wav")]
+    pub output: PathBuf,
+
+    /// Render as fast as possible without opening an audio device
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Audio API to use, e.g. alsa, jack or coreaudio [default: the platform's default]
+    #[arg(long, conflicts_with = "offline")]
+    pub host: Option<String>,
+
+    /// Output device to play through in realtime and live mode, by its index in
+    /// --list-devices, part of its name, or "default"
+    #[arg(long, default_value = "default")]
+    pub device: DeviceSelector,
+
+    /// List every host and device with the configs they support, then exit
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Play an input device live through the chain to the output device, instead of a file
+    #[arg(long, conflicts_with = "offline")]
+    pub live: bool,
+
+    /// Input device for live mode, by its index in --list-devices, part of its name, or "default"
+    #[arg(long, default_value = "default")]
+    pub input_device: DeviceSelector,
+
+    /// Most extra delay in milliseconds allowed to build up before the output in live mode
+    #[arg(long, default_value_t = 100.0, requires = "live")]
+    pub max_latency: f64,
+
+    /// Record the live input to this WAV file
+    #[arg(long, requires = "live")]
+    pub record_dry: Option<PathBuf>,
+
+    /// Record the live processed signal to this WAV file
+    #[arg(long, requires = "live")]
+    pub record_wet: Option<PathBuf>,
+
+    /// Built-in preset name or path to a preset TOML file
+    #[arg(short, long)]
+    pub preset: Option<String>,
+
+    /// Write the final settings, overrides included, to a preset TOML file
+    #[arg(long)]
+    pub save_preset: Option<PathBuf>,
+
+    /// List the built-in presets and exit
+    #[arg(long)]
+    pub list_presets: bool,
+
+    /// Probability of losing each packet, from 0.0 to 1.0
+    #[arg(long)]
+    pub loss: Option<f32>,
+
+    /// Average length of a loss burst in packets. Enables Gilbert-Elliott bursty loss.
+    #[arg(long)]
+    pub burst_length: Option<f32>,
+
+    /// One-way network latency in milliseconds
+    #[arg(long)]
+    pub latency: Option<f64>,
+
+    /// Maximum random extra delay per packet in milliseconds
+    #[arg(long)]
+    pub jitter: Option<f64>,
+
+    /// Loss probability over time: breakpoints like 0:0,30:0.4,60:0 (seconds:value),
+    /// lfo:<sine|triangle|square|saw>:<hz>:<min>:<max> or walk:<step per second>:<min>:<max>
+    #[arg(long)]
+    pub loss_curve: Option<Automation>,
+
+    /// Latency over time in milliseconds, in the same form as --loss-curve
+    #[arg(long)]
+    pub latency_curve: Option<Automation>,
+
+    /// Jitter over time in milliseconds, in the same form as --loss-curve
+    #[arg(long)]
+    pub jitter_curve: Option<Automation>,
+
+    /// Rate of a bottleneck link in kbit/s. Packets queue for it, so sending more
+    /// than it carries builds up delay and then drops.
+    #[arg(long)]
+    pub link_kbps: Option<f64>,
+
+    /// Size of the bottleneck queue in bytes [default: 100 ms at the link rate]
+    #[arg(long, requires = "link_kbps")]
+    pub queue_bytes: Option<usize>,
+
+    /// How the bottleneck queue drops packets
+    #[arg(long, value_enum, requires = "link_kbps")]
+    pub queue_discipline: Option<QueueKind>,
+
+    /// Chance of a packet being delivered twice
+    #[arg(long)]
+    pub duplicate: Option<f64>,
+
+    /// Chance of a packet being held back until later packets have overtaken it
+    #[arg(long)]
+    pub reorder: Option<f64>,
+
+    /// How many later packets overtake a reordered one [default: 1]
+    #[arg(long)]
+    pub reorder_depth: Option<u32>,
+
+    /// Chance of flipping each bit of the packets that arrive
+    #[arg(long)]
+    pub bit_error_rate: Option<f64>,
+
+    /// Chance of cutting a packet off at a random point
+    #[arg(long)]
+    pub truncate: Option<f64>,
+
+    /// Chance of zeroing a random run of bytes in a packet
+    #[arg(long)]
+    pub zero_bytes: Option<f64>,
+
+    /// Chance of copying a random run of bytes over another part of a packet
+    #[arg(long)]
+    pub repeat_bytes: Option<f64>,
+
+    /// CSV log (sequence,send_us,arrival_us) or pcap of an RTP stream whose loss and
+    /// delays are replayed instead of the simulated ones
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+
+    /// Step sequencer lane as <drop|freeze|repeat>:<pattern>, where the pattern is a
+    /// grid like x...x.5. (x hit, . rest, 1-9 for 10-90%) or a Euclidean rhythm like
+    /// e3/8+1, optionally ending in @<probability>. Repeat for more lanes; the first
+    /// to hit a step wins.
+    #[arg(long = "pattern")]
+    pub patterns: Vec<Lane>,
+
+    /// Tempo of the step sequencer in quarter notes per minute [default: 120]
+    #[arg(long, value_parser = parse_bpm)]
+    pub bpm: Option<f64>,
+
+    /// Time signature of the step sequencer, e.g. 7/8 [default: 4/4]
+    #[arg(long, value_parser = parse_time_signature)]
+    pub time_signature: Option<(u32, u32)>,
+
+    /// Step sequencer steps per quarter note [default: 4]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=64))]
+    pub steps_per_beat: Option<u32>,
+
+    /// Seed for the network simulator, to reproduce a previous run
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Jitter buffer depth in milliseconds, or the minimum depth with --adaptive
+    #[arg(long)]
+    pub buffer: Option<f64>,
+
+    /// Let the jitter buffer depth follow the measured network delay
+    #[arg(long)]
+    pub adaptive: bool,
+
+    /// Target bitrate in bits per second, down to 500 for very crunchy audio
+    #[arg(long, value_parser = clap::value_parser!(i32).range(500..=512_000))]
+    pub bitrate: Option<i32>,
+
+    /// Opus application mode
+    #[arg(long, value_enum)]
+    pub application: Option<ApplicationMode>,
+
+    /// Constant, variable or constrained variable bitrate
+    #[arg(long, value_enum)]
+    pub vbr: Option<VbrMode>,
+
+    /// Encoder complexity from 0 (cheapest) to 10 (best quality)
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=10))]
+    pub complexity: Option<i32>,
+
+    /// Highest audio bandwidth the encoder may use
+    #[arg(long, value_enum)]
+    pub bandwidth: Option<MaxBandwidth>,
+
+    /// Hint whether the input is voice or music
+    #[arg(long, value_enum)]
+    pub signal: Option<Signal>,
+
+    /// Enable discontinuous transmission, sending almost nothing during silence.
+    /// --dtx=false turns it off when a preset has it on.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub dtx: Option<bool>,
+
+    /// Opus frame duration in milliseconds
+    #[arg(long, value_parser = parse_frame_duration)]
+    pub frame_ms: Option<FrameDuration>,
+
+    /// Recover lost frames with Opus in-band FEC. --fec=false turns it off when a
+    /// preset has it on.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub fec: Option<bool>,
+
+    /// What plays in place of lost packets: plc (Opus concealment), silence,
+    /// repeat[:<times>] (the last packet again), hold[:<frames>] (loop the last
+    /// frames), reverse or crossfade
+    #[arg(long)]
+    pub on_loss: Option<LossStrategy>,
+
+    /// Share of the processed signal in the output, from 0.0 (only the input) to 1.0
+    #[arg(long)]
+    pub wet: Option<f32>,
+
+    /// Effect on the processed signal around lost packets: lowpass:<cutoff Hz>,
+    /// bitcrush:<bits>[:<downsample>] or duck:<gain dB>. Repeat to chain them.
+    #[arg(long = "loss-effect", allow_negative_numbers = true)]
+    pub loss_effects: Vec<LossEffect>,
+
+    /// Milliseconds the loss effects take to die away after a loss
+    #[arg(long)]
+    pub effect_release: Option<f64>,
+
+    /// Sample rate the codec runs at, in Hz
+    #[arg(long, value_parser = parse_codec_rate)]
+    pub codec_rate: Option<u32>,
+
+    /// Sample rate of the recording, in Hz [default: input rate offline, device rate otherwise]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub output_rate: Option<u32>,
+}
+
+impl Args {
+    pub fn apply_to(&self, preset: &mut Preset) {
+        let network = &mut preset.network;
+
+        // A new loss rate keeps the preset's burstiness unless a burst length is given too
+        if self.loss.is_some() || self.burst_length.is_some() {
+            let loss = self
+                .loss
+                .unwrap_or_else(|| network.loss.average_loss_probability());
+            let burst_length = self.burst_length.or(match &network.loss {
+                LossModel::GilbertElliott(model) => Some(model.mean_burst_length()),
+                _ => None,
+            });
+            network.loss = match burst_length {
+                Some(burst_length) => {
+                    LossModel::GilbertElliott(GilbertElliott::from_average_loss(loss, burst_length))
+                }
+                None => LossModel::Bernoulli {
+                    loss_probability: loss,
+                },
+            };
+        }
+
+        if let Some(latency) = self.latency {
+            network.latency_us = ms_to_us(latency);
+        }
+        if let Some(jitter) = self.jitter {
+            network.jitter_us = ms_to_us(jitter);
+        }
+        if self.seed.is_some() {
+            network.seed = self.seed;
+        }
+        if let Some(link_kbps) = self.link_kbps {
+            let rate_bps = (link_kbps * 1000.0).round() as u64;
+            let queue_bytes = self
+                .queue_bytes
+                .unwrap_or((rate_bps / 8 / 10).max(1500) as usize);
+            let discipline = self
+                .queue_discipline
+                .unwrap_or(QueueKind::DropTail)
+                .discipline(queue_bytes);
+            network.bottleneck =
+                Some(Bottleneck::new(rate_bps, queue_bytes).with_discipline(discipline));
+        }
+        let delivery = &mut network.delivery;
+        if let Some(duplicate) = self.duplicate {
+            delivery.duplicate_probability = duplicate;
+        }
+        if let Some(reorder) = self.reorder {
+            delivery.reorder_probability = reorder;
+        }
+        if let Some(reorder_depth) = self.reorder_depth {
+            delivery.reorder_depth = reorder_depth;
+        }
+
+        let corruption = &mut network.corruption;
+        if let Some(bit_error_rate) = self.bit_error_rate {
+            corruption.bit_error_rate = bit_error_rate;
+        }
+        if let Some(truncate) = self.truncate {
+            corruption.truncate_probability = truncate;
+        }
+        if let Some(zero_bytes) = self.zero_bytes {
+            corruption.zero_probability = zero_bytes;
+        }
+        if let Some(repeat_bytes) = self.repeat_bytes {
+            corruption.byte_repeat_probability = repeat_bytes;
+        }
+        if !self.patterns.is_empty()
+            || self.bpm.is_some()
+            || self.time_signature.is_some()
+            || self.steps_per_beat.is_some()
+        {
+            let sequencer = network.sequencer.get_or_insert_with(StepSequencer::default);
+            if !self.patterns.is_empty() {
+                sequencer.lanes = self.patterns.clone();
+            }
+            if let Some(bpm) = self.bpm {
+                sequencer.bpm = bpm;
+            }
+            if let Some((beats_per_bar, beat_unit)) = self.time_signature {
+                sequencer.beats_per_bar = beats_per_bar;
+                sequencer.beat_unit = beat_unit;
+            }
+            if let Some(steps_per_beat) = self.steps_per_beat {
+                sequencer.steps_per_beat = steps_per_beat;
+            }
+        }
+        if self.trace.is_some() {
+            network.trace = self.trace.clone();
+        }
+        if let Some(loss_curve) = &self.loss_curve {
+            network.automation.loss = Some(loss_curve.clone());
+        }
+        if let Some(latency_curve) = &self.latency_curve {
+            network.automation.latency_us = Some(latency_curve.clone().scaled(1000.0));
+        }
+        if let Some(jitter_curve) = &self.jitter_curve {
+            network.automation.jitter_us = Some(jitter_curve.clone().scaled(1000.0));
+        }
+
+        if self.buffer.is_some() || self.adaptive {
+            let (depth_us, max_depth_us) = match network.jitter_buffer {
+                JitterBufferMode::Fixed { depth_us } => (depth_us, None),
+                JitterBufferMode::Adaptive {
+                    min_depth_us,
+                    max_depth_us,
+                } => (min_depth_us, Some(max_depth_us)),
+            };
+            let depth_us = self.buffer.map(ms_to_us).unwrap_or(depth_us);
+            network.jitter_buffer = match max_depth_us.or(self.adaptive.then_some(200_000)) {
+                Some(max_depth_us) => JitterBufferMode::Adaptive {
+                    min_depth_us: depth_us,
+                    max_depth_us: max_depth_us.max(depth_us),
+                },
+                None => JitterBufferMode::Fixed { depth_us },
+            };
+        }
+
+        let codec = &mut preset.codec;
+        if self.bitrate.is_some() {
+            codec.bitrate = self.bitrate;
+        }
+        if let Some(application) = self.application {
+            codec.application = application;
+        }
+        if let Some(vbr) = self.vbr {
+            codec.vbr = vbr;
+        }
+        if self.complexity.is_some() {
+            codec.complexity = self.complexity;
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            codec.max_bandwidth = bandwidth;
+        }
+        if let Some(signal) = self.signal {
+            codec.signal = signal;
+        }
+        if let Some(dtx) = self.dtx {
+            codec.dtx = dtx;
+        }
+        if let Some(frame_ms) = self.frame_ms {
+            codec.frame_ms = frame_ms;
+        }
+        if let Some(fec) = self.fec {
+            codec.fec = fec;
+        }
+        if let Some(on_loss) = self.on_loss {
+            codec.loss_strategy = on_loss;
+        }
+        if let Some(codec_rate) = self.codec_rate {
+            codec.sample_rate = codec_rate;
+        }
+
+        let mix = &mut preset.mix;
+        if let Some(wet) = self.wet {
+            mix.wet = wet;
+        }
+        if !self.loss_effects.is_empty() {
+            mix.loss_effects = self.loss_effects.clone();
+        }
+        if let Some(effect_release) = self.effect_release {
+            mix.release_ms = effect_release;
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum QueueKind {
+    DropTail,
+    Red,
+    Codel,
+}
+
+impl QueueKind {
+    // Commonly used settings for each discipline
+    fn discipline(self, queue_bytes: usize) -> QueueDiscipline {
+        match self {
+            QueueKind::DropTail => QueueDiscipline::DropTail,
+            QueueKind::Red => QueueDiscipline::Red {
+                min_threshold_bytes: queue_bytes / 4,
+                max_threshold_bytes: queue_bytes * 3 / 4,
+                max_probability: 0.1,
+            },
+            QueueKind::Codel => QueueDiscipline::Codel {
+                target_us: 5_000,
+                interval_us: 100_000,
+            },
+        }
+    }
+}
+
+fn parse_frame_duration(value: &str) -> Result<FrameDuration, String> {
+    let ms = value.parse::<f32>().map_err(|err| err.to_string())?;
+    FrameDuration::try_from(ms)
+}
+
+fn parse_bpm(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(bpm) if bpm.is_finite() && bpm > 0.0 => Ok(bpm),
+        _ => Err(format!("expected a tempo above 0, got '{value}'")),
+    }
+}
+
+fn parse_time_signature(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("expected a time signature like 4/4 or 7/8, got '{value}'");
+    let (beats_per_bar, beat_unit) = value.split_once('/').ok_or_else(invalid)?;
+    let beats_per_bar: u32 = beats_per_bar.parse().map_err(|_| invalid())?;
+    let beat_unit: u32 = beat_unit.parse().map_err(|_| invalid())?;
+    if beats_per_bar == 0 || !beat_unit.is_power_of_two() {
+        return Err(invalid());
+    }
+    Ok((beats_per_bar, beat_unit))
+}
+
+fn parse_codec_rate(value: &str) -> Result<u32, String> {
+    let rate = value.parse::<u32>().map_err(|err| err.to_string())?;
+    check_sample_rate(rate).map_err(|err| err.to_string())?;
+    Ok(rate)
+}
+
+// Converts a duration in milliseconds from the command line to whole microseconds
+pub fn ms_to_us(ms: f64) -> u64 {
+    (ms * 1000.0).round().max(0.0) as u64
+}
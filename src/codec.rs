@@ -0,0 +1,223 @@
+use crate::loss_strategy::LossStrategy;
+use crate::opus_encoder::OpusEncoder;
+use clap::ValueEnum;
+use opus::{Bandwidth, Bitrate, Channels, Decoder};
+use serde::{Deserialize, Serialize};
+
+// Opus settings for the sending side. Anything left out of a preset file keeps
+// its default, which mostly means letting Opus decide.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CodecConfig {
+    pub application: ApplicationMode,
+    // Target bitrate in bits per second, chosen by Opus when not set. Opus accepts
+    // anything from 500 b/s, which is where the crunchy artifacts live.
+    pub bitrate: Option<i32>,
+    pub vbr: VbrMode,
+    // 0 to 10, trading encoder CPU for quality
+    pub complexity: Option<i32>,
+    pub max_bandwidth: MaxBandwidth,
+    pub signal: Signal,
+    // Discontinuous transmission: send almost nothing during silence
+    pub dtx: bool,
+    pub frame_ms: FrameDuration,
+    // Rate the codec runs at. Opus only supports 8, 12, 16, 24 and 48 kHz, and
+    // anything else is converted to and from this rate.
+    pub sample_rate: u32,
+    // Recover lost frames from in-band FEC data in the following packet
+    pub fec: bool,
+    // What the receiver plays for frames FEC can't recover
+    pub loss_strategy: LossStrategy,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        Self {
+            application: ApplicationMode::Voip,
+            bitrate: None,
+            vbr: VbrMode::ConstrainedVbr,
+            complexity: None,
+            max_bandwidth: MaxBandwidth::Fullband,
+            signal: Signal::Auto,
+            dtx: false,
+            frame_ms: FrameDuration::Ms20,
+            sample_rate: 48000,
+            fec: false,
+            loss_strategy: LossStrategy::Plc,
+        }
+    }
+}
+
+impl CodecConfig {
+    pub fn build_encoder(&self) -> Result<OpusEncoder, anyhow::Error> {
+        check_sample_rate(self.sample_rate)?;
+        let mut encoder =
+            OpusEncoder::new(self.sample_rate, Channels::Stereo, self.application.into())?;
+        if let Some(bitrate) = self.bitrate {
+            encoder.set_bitrate(Bitrate::Bits(bitrate))?;
+        }
+        encoder.set_vbr(self.vbr != VbrMode::Cbr)?;
+        encoder.set_vbr_constraint(self.vbr == VbrMode::ConstrainedVbr)?;
+        if let Some(complexity) = self.complexity {
+            encoder.set_complexity(complexity)?;
+        }
+        encoder.set_max_bandwidth(self.max_bandwidth.into())?;
+        encoder.set_signal(self.signal)?;
+        encoder.set_dtx(self.dtx)?;
+        Ok(encoder)
+    }
+
+    pub fn build_decoder(&self) -> Result<Decoder, anyhow::Error> {
+        check_sample_rate(self.sample_rate)?;
+        Ok(Decoder::new(self.sample_rate, Channels::Stereo)?)
+    }
+}
+
+pub const SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+pub fn check_sample_rate(sample_rate: u32) -> Result<(), anyhow::Error> {
+    if SAMPLE_RATES.contains(&sample_rate) {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "Opus can't run at {sample_rate} Hz, use one of {SAMPLE_RATES:?}"
+        )))
+    }
+}
+
+// Every frame duration Opus can encode. Anything above 20 ms is sent as one
+// packet holding several 20 ms (or longer CELT) frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "f32", into = "f32")]
+pub enum FrameDuration {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+    Ms80,
+    Ms100,
+    Ms120,
+}
+
+impl FrameDuration {
+    pub const ALL: [FrameDuration; 9] = [
+        FrameDuration::Ms2_5,
+        FrameDuration::Ms5,
+        FrameDuration::Ms10,
+        FrameDuration::Ms20,
+        FrameDuration::Ms40,
+        FrameDuration::Ms60,
+        FrameDuration::Ms80,
+        FrameDuration::Ms100,
+        FrameDuration::Ms120,
+    ];
+
+    // The longest packet duration the decoder can ever be handed
+    pub const MAX: FrameDuration = FrameDuration::Ms120;
+
+    pub fn duration_us(self) -> u64 {
+        match self {
+            FrameDuration::Ms2_5 => 2_500,
+            FrameDuration::Ms5 => 5_000,
+            FrameDuration::Ms10 => 10_000,
+            FrameDuration::Ms20 => 20_000,
+            FrameDuration::Ms40 => 40_000,
+            FrameDuration::Ms60 => 60_000,
+            FrameDuration::Ms80 => 80_000,
+            FrameDuration::Ms100 => 100_000,
+            FrameDuration::Ms120 => 120_000,
+        }
+    }
+
+    // Samples per channel in each frame
+    pub fn frame_size(self, sample_rate: u32) -> usize {
+        (sample_rate as u64 * self.duration_us() / 1_000_000) as usize
+    }
+
+    // Worst case packet size: each 20 ms sub-frame can take up to 1275 bytes plus
+    // a couple of bytes to store its length, and the packet has a short header
+    pub fn max_packet_size(self) -> usize {
+        let sub_frames = self.duration_us().div_ceil(20_000) as usize;
+        sub_frames * (1275 + 2) + 2
+    }
+}
+
+impl TryFrom<f32> for FrameDuration {
+    type Error = String;
+
+    fn try_from(ms: f32) -> Result<Self, Self::Error> {
+        FrameDuration::ALL
+            .into_iter()
+            .find(|duration| f32::from(*duration) == ms)
+            .ok_or_else(|| "expected one of 2.5, 5, 10, 20, 40, 60, 80, 100 or 120 ms".to_string())
+    }
+}
+
+impl From<FrameDuration> for f32 {
+    fn from(duration: FrameDuration) -> Self {
+        duration.duration_us() as f32 / 1000.0
+    }
+}
+
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApplicationMode {
+    Voip,
+    Audio,
+    LowDelay,
+}
+
+impl From<ApplicationMode> for opus::Application {
+    fn from(mode: ApplicationMode) -> Self {
+        match mode {
+            ApplicationMode::Voip => opus::Application::Voip,
+            ApplicationMode::Audio => opus::Application::Audio,
+            ApplicationMode::LowDelay => opus::Application::LowDelay,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VbrMode {
+    // Every packet is the same size
+    Cbr,
+    Vbr,
+    // Varies packet sizes, but keeps close to the target bitrate over short spans
+    ConstrainedVbr,
+}
+
+// Highest audio bandwidth the encoder may use, from 4 kHz up to 20 kHz
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaxBandwidth {
+    Narrowband,
+    Mediumband,
+    Wideband,
+    Superwideband,
+    Fullband,
+}
+
+impl From<MaxBandwidth> for Bandwidth {
+    fn from(bandwidth: MaxBandwidth) -> Self {
+        match bandwidth {
+            MaxBandwidth::Narrowband => Bandwidth::Narrowband,
+            MaxBandwidth::Mediumband => Bandwidth::Mediumband,
+            MaxBandwidth::Wideband => Bandwidth::Wideband,
+            MaxBandwidth::Superwideband => Bandwidth::Superwideband,
+            MaxBandwidth::Fullband => Bandwidth::Fullband,
+        }
+    }
+}
+
+// Hint about the material being encoded, which steers Opus between its speech
+// (SILK) and music (CELT) modes
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Signal {
+    Auto,
+    Voice,
+    Music,
+}
@@ -0,0 +1,131 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Damage done to packets that make it through the network, handed to the decoder
+// as is. Every probability defaults to zero, which leaves packets untouched.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Corruption {
+    // Chance of each bit being flipped
+    pub bit_error_rate: f64,
+    // Chance of a packet being cut off at a random point
+    pub truncate_probability: f64,
+    // Chance of a random run of bytes being zeroed
+    pub zero_probability: f64,
+    // Chance of a random run of bytes being copied over another part of the packet
+    pub byte_repeat_probability: f64,
+}
+
+impl Corruption {
+    pub fn is_disabled(&self) -> bool {
+        self.bit_error_rate <= 0.0
+            && self.truncate_probability <= 0.0
+            && self.zero_probability <= 0.0
+            && self.byte_repeat_probability <= 0.0
+    }
+
+    // Mangles `payload` in place, returning whether anything was changed. Each mode
+    // only draws random numbers when enabled, so turning corruption off leaves the
+    // rest of a seeded run as it was.
+    pub fn corrupt<R: Rng>(&self, payload: &mut Vec<u8>, rng: &mut R) -> bool {
+        let mut corrupted = false;
+        if payload.is_empty() {
+            return corrupted;
+        }
+
+        if self.bit_error_rate > 0.0 {
+            for byte in payload.iter_mut() {
+                for bit in 0..8 {
+                    if rng.gen::<f64>() < self.bit_error_rate {
+                        *byte ^= 1 << bit;
+                        corrupted = true;
+                    }
+                }
+            }
+        }
+
+        if self.zero_probability > 0.0 && rng.gen::<f64>() < self.zero_probability {
+            let (start, len) = random_run(payload.len(), rng);
+            payload[start..start + len].fill(0);
+            corrupted = true;
+        }
+
+        if self.byte_repeat_probability > 0.0 && rng.gen::<f64>() < self.byte_repeat_probability {
+            let (source, len) = random_run(payload.len(), rng);
+            let destination = rng.gen_range(0..=payload.len() - len);
+            payload.copy_within(source..source + len, destination);
+            corrupted = true;
+        }
+
+        // Last, so the other modes see the whole packet
+        if self.truncate_probability > 0.0 && rng.gen::<f64>() < self.truncate_probability {
+            payload.truncate(rng.gen_range(0..payload.len()));
+            corrupted = true;
+        }
+
+        corrupted
+    }
+}
+
+// Start and length of a random run of at least one byte in a buffer of `len` bytes
+fn random_run<R: Rng>(len: usize, rng: &mut R) -> (usize, usize) {
+    let start = rng.gen_range(0..len);
+    let run_len = rng.gen_range(1..=len - start);
+    (start, run_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn flips_bits_at_the_error_rate() {
+        let corruption = Corruption {
+            bit_error_rate: 0.01,
+            ..Corruption::default()
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+        let original = vec![0x5a; 10_000];
+        let mut payload = original.clone();
+        assert!(corruption.corrupt(&mut payload, &mut rng));
+
+        let flipped: u32 = original
+            .iter()
+            .zip(&payload)
+            .map(|(before, after)| (before ^ after).count_ones())
+            .sum();
+        // 80,000 bits at 1%
+        assert!((700..900).contains(&flipped), "flipped {flipped} bits");
+    }
+
+    #[test]
+    fn disabled_leaves_packets_alone() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut payload = vec![1, 2, 3, 4];
+        assert!(!Corruption::default().corrupt(&mut payload, &mut rng));
+        assert_eq!(payload, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn truncates_and_zeroes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let truncate = Corruption {
+            truncate_probability: 1.0,
+            ..Corruption::default()
+        };
+        let mut payload = vec![1; 100];
+        assert!(truncate.corrupt(&mut payload, &mut rng));
+        assert!(payload.len() < 100);
+
+        let zero = Corruption {
+            zero_probability: 1.0,
+            ..Corruption::default()
+        };
+        let mut payload = vec![1; 100];
+        assert!(zero.corrupt(&mut payload, &mut rng));
+        assert_eq!(payload.len(), 100);
+        assert!(payload.contains(&0));
+    }
+}
@@ -0,0 +1,198 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::SampleRate;
+use std::str::FromStr;
+
+// Rate to ask output devices for, if they support it
+const PREFERRED_SAMPLE_RATE: u32 = 48000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+// Which device to use, as given on the command line: `default`, a position in
+// the `--list-devices` listing, or part of the device's name in any case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Default,
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.is_empty() {
+            return Err("device can't be empty".to_string());
+        }
+        Ok(if value.eq_ignore_ascii_case("default") {
+            DeviceSelector::Default
+        } else if let Ok(index) = value.parse() {
+            DeviceSelector::Index(index)
+        } else {
+            DeviceSelector::Name(value.to_string())
+        })
+    }
+}
+
+// The audio API to use, matched by part of its name (e.g. ALSA, JACK, CoreAudio,
+// WASAPI), or the platform default
+pub fn host(name: Option<&str>) -> Result<cpal::Host, anyhow::Error> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|host_id| host_id.name().to_lowercase().contains(&name.to_lowercase()))
+        .ok_or_else(|| {
+            let available: Vec<_> = cpal::available_hosts()
+                .iter()
+                .map(|host_id| host_id.name())
+                .collect();
+            anyhow::Error::msg(format!(
+                "no audio host matching '{name}', available: {}",
+                available.join(", ")
+            ))
+        })?;
+    Ok(cpal::host_from_id(host_id)?)
+}
+
+// Picks a device. A selector that doesn't match anything falls back to the
+// default device, and a missing default to the first device there is.
+pub fn select(
+    host: &cpal::Host,
+    direction: Direction,
+    selector: &DeviceSelector,
+) -> Result<cpal::Device, anyhow::Error> {
+    let mut candidates = devices(host, direction)?;
+    let selected = match selector {
+        DeviceSelector::Default => None,
+        DeviceSelector::Index(index) => {
+            (*index < candidates.len()).then(|| candidates.remove(*index))
+        }
+        DeviceSelector::Name(name) => {
+            let name = name.to_lowercase();
+            candidates
+                .iter()
+                .position(|device| device_name(device).to_lowercase().contains(&name))
+                .map(|index| candidates.remove(index))
+        }
+    };
+    if let Some(device) = selected {
+        return Ok(device);
+    }
+    if *selector != DeviceSelector::Default {
+        eprintln!("No {direction:?} device matching {selector:?}, using the default instead");
+    }
+
+    let default = match direction {
+        Direction::Input => host.default_input_device(),
+        Direction::Output => host.default_output_device(),
+    };
+    default
+        .or_else(|| candidates.into_iter().next())
+        .ok_or_else(|| anyhow::Error::msg(format!("no {direction:?} device available")))
+}
+
+// Prints every host, its devices in index order and what each one supports
+pub fn list() {
+    for host_id in cpal::available_hosts() {
+        println!("Host: {}", host_id.name());
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(err) => {
+                println!("  unavailable: {err}");
+                continue;
+            }
+        };
+        for direction in [Direction::Input, Direction::Output] {
+            println!("  {direction:?} devices:");
+            let devices = match devices(&host, direction) {
+                Ok(devices) => devices,
+                Err(err) => {
+                    println!("    unavailable: {err}");
+                    continue;
+                }
+            };
+            for (index, device) in devices.iter().enumerate() {
+                println!("    {index}: {}", device_name(device));
+                for config in supported_configs(device, direction) {
+                    println!(
+                        "         {} ch, {}-{} Hz, {}",
+                        config.channels(),
+                        config.min_sample_rate().0,
+                        config.max_sample_rate().0,
+                        config.sample_format()
+                    );
+                }
+            }
+        }
+    }
+}
+
+// A stereo output config, at 48 kHz if possible and otherwise at the highest
+// rate the device offers, since audio gets resampled either way
+pub fn stereo_output_config(
+    device: &cpal::Device,
+) -> Result<cpal::SupportedStreamConfig, anyhow::Error> {
+    let desired_sample_rate = SampleRate(PREFERRED_SAMPLE_RATE);
+    let stereo_configs: Vec<_> = device
+        .supported_output_configs()?
+        .filter(|config| config.channels() == 2)
+        .collect();
+    let config = match stereo_configs.iter().find(|config| {
+        config.min_sample_rate() <= desired_sample_rate
+            && config.max_sample_rate() >= desired_sample_rate
+    }) {
+        Some(config) => config.with_sample_rate(desired_sample_rate),
+        None => stereo_configs
+            .into_iter()
+            .max_by_key(|config| config.max_sample_rate())
+            .ok_or_else(|| anyhow::Error::msg("no supported stereo output config"))?
+            .with_max_sample_rate(),
+    };
+    Ok(config)
+}
+
+// The device's own default input config, or failing that whatever it supports
+// at the highest rate
+pub fn input_config(device: &cpal::Device) -> Result<cpal::SupportedStreamConfig, anyhow::Error> {
+    if let Ok(config) = device.default_input_config() {
+        return Ok(config);
+    }
+    device
+        .supported_input_configs()?
+        .max_by_key(|config| config.max_sample_rate())
+        .map(|config| config.with_max_sample_rate())
+        .ok_or_else(|| anyhow::Error::msg("no supported input config"))
+}
+
+pub fn device_name(device: &cpal::Device) -> String {
+    device
+        .name()
+        .unwrap_or_else(|_| "<unknown device>".to_string())
+}
+
+fn devices(host: &cpal::Host, direction: Direction) -> Result<Vec<cpal::Device>, anyhow::Error> {
+    Ok(match direction {
+        Direction::Input => host.input_devices()?.collect(),
+        Direction::Output => host.output_devices()?.collect(),
+    })
+}
+
+fn supported_configs(
+    device: &cpal::Device,
+    direction: Direction,
+) -> Vec<cpal::SupportedStreamConfigRange> {
+    let configs = match direction {
+        Direction::Input => device
+            .supported_input_configs()
+            .map(|configs| configs.collect()),
+        Direction::Output => device
+            .supported_output_configs()
+            .map(|configs| configs.collect()),
+    };
+    configs.unwrap_or_default()
+}
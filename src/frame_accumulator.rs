@@ -0,0 +1,37 @@
+// Collects interleaved samples from audio callbacks of any size and hands them
+// out as fixed-size frames, carrying leftovers over to the next callback
+pub struct FrameAccumulator {
+    buffer: Vec<f32>,
+    frame: Vec<f32>,
+}
+
+impl FrameAccumulator {
+    // `frame_len` counts samples across all channels, e.g. 960 * 2 for a 20 ms stereo frame
+    pub fn new(frame_len: usize) -> Self {
+        Self {
+            buffer: Vec::with_capacity(frame_len * 2),
+            frame: vec![0.0; frame_len],
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        self.buffer.push(sample);
+    }
+
+    // Samples pushed that don't make up a whole frame yet
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Returns the next complete frame, or None until enough samples have been pushed
+    pub fn next_frame(&mut self) -> Option<&[f32]> {
+        let frame_len = self.frame.len();
+        if self.buffer.len() < frame_len {
+            return None;
+        }
+
+        self.frame.copy_from_slice(&self.buffer[..frame_len]);
+        self.buffer.drain(..frame_len);
+        Some(&self.frame)
+    }
+}
@@ -0,0 +1,301 @@
+use crate::automation::Waveform;
+use crate::codec::{ApplicationMode, CodecConfig, FrameDuration};
+use crate::frame_accumulator::FrameAccumulator;
+use crate::jitter_buffer::{JitterBuffer, JitterBufferMode};
+use crate::loss_model::{GilbertElliott, LossModel};
+use crate::loss_strategy::LossStrategy;
+use crate::mixer::Mixer;
+use crate::network_channel::NetworkChannel;
+use crate::network_simulator::NetworkSimulator;
+use crate::pipeline::{Pipeline, CHANNELS};
+use crate::resampler::Resampler;
+use opus::Bitrate;
+use std::collections::VecDeque;
+
+// Rate the codec runs at, whatever the host's rate
+const CODEC_RATE: u32 = 48000;
+// Tempo to sync to when the host doesn't say
+const DEFAULT_TEMPO_BPM: f64 = 120.0;
+
+// What the effect can be set to from the outside, e.g. by a plugin host
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlitchSettings {
+    // Average share of packets lost, from 0.0 to 1.0
+    pub loss: f32,
+    // Average length of a loss burst in packets
+    pub burst_length: f32,
+    pub latency_ms: f64,
+    pub jitter_ms: f64,
+    pub bitrate: i32,
+    pub frame_ms: FrameDuration,
+    // Jitter buffer depth. Packets taking longer than this to arrive play as lost.
+    pub buffer_ms: f64,
+    // Beats per cycle of the loss following the host's tempo, or None for steady loss
+    pub sync_beats: Option<f64>,
+    // How the loss rises and falls over each synced cycle
+    pub sync_shape: Waveform,
+    // What plays in place of lost packets
+    pub loss_strategy: LossStrategy,
+    // Share of the processed signal in the output, the rest being the input
+    pub wet: f32,
+}
+
+impl Default for GlitchSettings {
+    fn default() -> Self {
+        Self {
+            loss: 0.1,
+            burst_length: 2.0,
+            latency_ms: 20.0,
+            jitter_ms: 10.0,
+            bitrate: 32_000,
+            frame_ms: FrameDuration::Ms20,
+            buffer_ms: 60.0,
+            sync_beats: None,
+            sync_shape: Waveform::Sine,
+            loss_strategy: LossStrategy::Plc,
+            wet: 1.0,
+        }
+    }
+}
+
+// Where the host's transport is at the start of a block
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transport {
+    pub tempo_bpm: Option<f64>,
+    // Position in quarter notes, if the host is playing along a timeline
+    pub beats: Option<f64>,
+}
+
+// The encoder -> network -> decoder chain as a streaming stereo effect with a fixed
+// delay. Output is held back by `latency_frames`, so the chain always has its
+// next samples ready however the host slices its blocks, and a host can line the
+// effect up with the rest of a mix.
+pub struct GlitchEffect {
+    settings: GlitchSettings,
+    sample_rate: u32,
+    input_resampler: Resampler,
+    accumulator: FrameAccumulator,
+    pipeline: Pipeline,
+    output_resampler: Resampler,
+    // Processed audio waiting to be played, primed with silence
+    pending: VecDeque<f32>,
+    latency_frames: u32,
+    // Beat position kept up locally for when the host doesn't report one
+    beats: f64,
+    // Blocks that found less audio ready than they needed, which shouldn't happen
+    underruns: u64,
+    // Scratch space, kept to avoid allocating on every block
+    interleaved: Vec<f32>,
+    resampled: Vec<f32>,
+    decoded: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl GlitchEffect {
+    pub fn new(settings: GlitchSettings, sample_rate: u32) -> Result<Self, anyhow::Error> {
+        if sample_rate == 0 {
+            return Err(anyhow::Error::msg("Host sample rate can't be 0 Hz"));
+        }
+        let codec = CodecConfig {
+            application: ApplicationMode::Audio,
+            bitrate: Some(settings.bitrate),
+            frame_ms: settings.frame_ms,
+            sample_rate: CODEC_RATE,
+            ..CodecConfig::default()
+        };
+        let mut encoder = codec.build_encoder()?;
+        let encoder_lookahead = encoder.lookahead()? as u64;
+        let decoder = codec.build_decoder()?;
+
+        let network = NetworkSimulator::new(
+            0.0,
+            ms_to_us(settings.latency_ms),
+            ms_to_us(settings.jitter_ms),
+        )
+        .with_loss_model(loss_model(&settings));
+        let frame_duration_us = settings.frame_ms.duration_us();
+        let depth_us = ms_to_us(settings.buffer_ms);
+        let jitter_buffer =
+            JitterBuffer::new(JitterBufferMode::Fixed { depth_us }, frame_duration_us);
+        let pipeline = Pipeline::new(
+            encoder,
+            decoder,
+            NetworkChannel::new(network, jitter_buffer),
+            settings.frame_ms,
+            CODEC_RATE,
+        )
+        .with_loss_handler(settings.loss_strategy.build())
+        .with_mixer(Mixer::new(settings.wet, CODEC_RATE))?;
+
+        let input_resampler = Resampler::new(sample_rate, CODEC_RATE, CHANNELS);
+        let output_resampler = Resampler::new(CODEC_RATE, sample_rate, CHANNELS);
+
+        // Frame n is played once frame n + depth / frame duration has been sent,
+        // which can only happen once all of that frame is in. The resamplers need
+        // a few samples past each position too.
+        let frame_size = pipeline.frame_size() as u64;
+        let buffered_frames = depth_us.div_ceil(frame_duration_us);
+        let codec_lag =
+            (buffered_frames + 1) * frame_size + output_resampler.lookahead_frames() as u64;
+        let lag = input_resampler.lookahead_frames() as u64
+            + (codec_lag * sample_rate as u64).div_ceil(CODEC_RATE as u64)
+            + 1;
+        // On top of that the encoder's lookahead delays the decoded signal itself
+        let encoder_delay = (encoder_lookahead * sample_rate as u64).div_ceil(CODEC_RATE as u64);
+
+        Ok(Self {
+            settings,
+            sample_rate,
+            input_resampler,
+            accumulator: FrameAccumulator::new(frame_size as usize * CHANNELS),
+            pipeline,
+            output_resampler,
+            pending: VecDeque::from(vec![0.0; lag as usize * CHANNELS]),
+            latency_frames: (lag + encoder_delay) as u32,
+            beats: 0.0,
+            underruns: 0,
+            interleaved: Vec::new(),
+            resampled: Vec::new(),
+            decoded: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    // Makes room for blocks of up to `max_frames` frames, so processing them doesn't
+    // have to allocate
+    pub fn reserve_block(&mut self, max_frames: usize) {
+        let samples = max_frames * CHANNELS;
+        // A block can finish a frame started in the one before, and the resamplers
+        // hold a little back
+        let frame_len = self.pipeline.frame_size() * CHANNELS;
+        let codec_samples =
+            (samples as u64 * CODEC_RATE as u64).div_ceil(self.sample_rate as u64) as usize;
+        self.interleaved.reserve(samples);
+        self.resampled.reserve(codec_samples + CHANNELS);
+        self.decoded.reserve(codec_samples + 2 * frame_len);
+        self.output.reserve(samples + 2 * frame_len);
+        self.pending.reserve(2 * (samples + frame_len));
+    }
+
+    // Delay from input to output, in frames at the host's rate
+    pub fn latency_frames(&self) -> u32 {
+        self.latency_frames
+    }
+
+    pub fn settings(&self) -> &GlitchSettings {
+        &self.settings
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    // Whether `settings` change the frame size or buffer depth, and so the
+    // latency, which only a new effect can pick up
+    pub fn needs_rebuild(&self, settings: &GlitchSettings) -> bool {
+        settings.frame_ms != self.settings.frame_ms || settings.buffer_ms != self.settings.buffer_ms
+    }
+
+    // Applies everything else while running
+    pub fn update(&mut self, settings: &GlitchSettings) -> Result<(), anyhow::Error> {
+        if settings.bitrate != self.settings.bitrate {
+            self.pipeline
+                .encoder_mut()
+                .set_bitrate(Bitrate::Bits(settings.bitrate))?;
+        }
+        if settings.loss_strategy != self.settings.loss_strategy {
+            self.pipeline
+                .set_loss_handler(settings.loss_strategy.build());
+        }
+        if let Some(mixer) = self.pipeline.mixer_mut() {
+            mixer.set_wet(settings.wet);
+        }
+        let network = self.pipeline.channel_mut().network_mut();
+        if settings.burst_length != self.settings.burst_length {
+            network.loss_model = loss_model(settings);
+        }
+        network.latency_us = ms_to_us(settings.latency_ms);
+        network.jitter_us = ms_to_us(settings.jitter_ms);
+
+        let frame_ms = self.settings.frame_ms;
+        let buffer_ms = self.settings.buffer_ms;
+        self.settings = GlitchSettings {
+            frame_ms,
+            buffer_ms,
+            ..settings.clone()
+        };
+        Ok(())
+    }
+
+    // Runs a block of stereo audio through the chain in place
+    pub fn process(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+        transport: Transport,
+    ) -> Result<(), anyhow::Error> {
+        let frames = left.len().min(right.len());
+        self.apply_sync(transport, frames);
+
+        self.interleaved.clear();
+        for (&left, &right) in left.iter().zip(right.iter()) {
+            self.interleaved.extend_from_slice(&[left, right]);
+        }
+        self.resampled.clear();
+        self.input_resampler
+            .process(&self.interleaved, &mut self.resampled);
+        for &sample in &self.resampled {
+            self.accumulator.push(sample);
+        }
+
+        self.decoded.clear();
+        while let Some(frame) = self.accumulator.next_frame() {
+            self.pipeline.process(frame, &mut self.decoded)?;
+        }
+        self.output.clear();
+        self.output_resampler
+            .process(&self.decoded, &mut self.output);
+        self.pending.extend(&self.output);
+
+        if self.pending.len() < frames * CHANNELS {
+            self.underruns += 1;
+        }
+        for (left, right) in left.iter_mut().zip(right.iter_mut()).take(frames) {
+            *left = self.pending.pop_front().unwrap_or(0.0);
+            *right = self.pending.pop_front().unwrap_or(0.0);
+        }
+        Ok(())
+    }
+
+    // Sets this block's loss from where it falls in the synced cycle
+    fn apply_sync(&mut self, transport: Transport, frames: usize) {
+        let tempo_bpm = transport.tempo_bpm.unwrap_or(DEFAULT_TEMPO_BPM);
+        if let Some(beats) = transport.beats {
+            self.beats = beats;
+        }
+        let loss = match self.settings.sync_beats {
+            Some(sync_beats) if sync_beats > 0.0 => {
+                let position = (self.beats / sync_beats).rem_euclid(1.0);
+                self.settings.loss * self.settings.sync_shape.unipolar(position) as f32
+            }
+            _ => self.settings.loss,
+        };
+        self.pipeline
+            .channel_mut()
+            .network_mut()
+            .loss_model
+            .set_average_loss_probability(loss);
+        self.beats += frames as f64 / self.sample_rate as f64 * tempo_bpm / 60.0;
+    }
+}
+
+fn loss_model(settings: &GlitchSettings) -> LossModel {
+    LossModel::GilbertElliott(GilbertElliott::from_average_loss(
+        settings.loss,
+        settings.burst_length,
+    ))
+}
+
+fn ms_to_us(ms: f64) -> u64 {
+    (ms.max(0.0) * 1000.0) as u64
+}
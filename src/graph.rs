@@ -0,0 +1,286 @@
+use crate::audio_file;
+use crate::frame_accumulator::FrameAccumulator;
+use crate::pipeline::{Pipeline, CHANNELS};
+use crate::resampler::Resampler;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// Frames a graph pulls from its source at a time
+const BLOCK_FRAMES: usize = 1024;
+
+// Everything in a graph passes interleaved stereo f32 samples in [-1.0, 1.0]
+// from one node to the next, with each stage free to change the sample rate.
+
+// Where audio comes from
+pub trait Source {
+    fn sample_rate(&self) -> u32;
+
+    // Appends up to `max_frames` frames to `output`, returning false once there's
+    // nothing left
+    fn read(&mut self, output: &mut Vec<f32>, max_frames: usize) -> Result<bool, anyhow::Error>;
+}
+
+// Something audio passes through on its way from a source to the sinks
+pub trait Stage: Send {
+    // Rate of what comes out when fed audio at `input_rate`
+    fn output_rate(&self, input_rate: u32) -> u32 {
+        input_rate
+    }
+
+    // Takes any number of frames and appends whatever output is ready to `output`
+    fn process(&mut self, input: &[f32], output: &mut Vec<f32>) -> Result<(), anyhow::Error>;
+
+    // Appends everything still held back once the input has ended
+    fn flush(&mut self, output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        let _ = output;
+        Ok(())
+    }
+
+    // A summary of what the stage did, for once the run is over
+    fn stats(&self) -> Option<String> {
+        None
+    }
+}
+
+// Where audio ends up
+pub trait Sink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), anyhow::Error>;
+
+    // Called once after the last write
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+// Any closure taking blocks of samples can be a sink
+impl<F> Sink for F
+where
+    F: FnMut(&[f32]) -> Result<(), anyhow::Error>,
+{
+    fn write(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+        self(samples)
+    }
+}
+
+// A source feeding a chain of stages, with every sink getting the end result.
+// Nothing runs in real time: the whole input is pushed through as fast as the
+// stages can take it.
+pub struct Graph {
+    source: Box<dyn Source>,
+    stages: Vec<Box<dyn Stage>>,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Graph {
+    pub fn new(source: impl Source + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            stages: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn with_stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn with_sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    // Rate of the audio that reaches the sinks
+    pub fn output_rate(&self) -> u32 {
+        self.stages
+            .iter()
+            .fold(self.source.sample_rate(), |rate, stage| {
+                stage.output_rate(rate)
+            })
+    }
+
+    // Pulls the source dry, flushes every stage in order and finishes the sinks
+    pub fn run(&mut self) -> Result<(), anyhow::Error> {
+        let mut block = Vec::new();
+        while self.source.read(&mut block, BLOCK_FRAMES)? {
+            let output = Self::process(&mut self.stages, block.split_off(0))?;
+            self.write(&output)?;
+        }
+
+        // Whatever each stage held back still has to go through the ones after it
+        for index in 0..self.stages.len() {
+            let mut flushed = Vec::new();
+            self.stages[index].flush(&mut flushed)?;
+            let output = Self::process(&mut self.stages[index + 1..], flushed)?;
+            self.write(&output)?;
+        }
+
+        for sink in &mut self.sinks {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+
+    // The summaries of the stages that have one, in order
+    pub fn stats(&self) -> Vec<String> {
+        self.stages
+            .iter()
+            .filter_map(|stage| stage.stats())
+            .collect()
+    }
+
+    fn process(stages: &mut [Box<dyn Stage>], input: Vec<f32>) -> Result<Vec<f32>, anyhow::Error> {
+        let mut samples = input;
+        for stage in stages {
+            let mut output = Vec::new();
+            stage.process(&samples, &mut output)?;
+            samples = output;
+        }
+        Ok(samples)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        for sink in &mut self.sinks {
+            sink.write(samples)?;
+        }
+        Ok(())
+    }
+}
+
+// Audio already in memory
+pub struct BufferSource {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    position: usize,
+}
+
+impl BufferSource {
+    pub fn new(samples: Vec<f32>, sample_rate: u32) -> Self {
+        Self {
+            samples,
+            sample_rate,
+            position: 0,
+        }
+    }
+
+    // Reads a whole WAV file of any channel count, mapped to stereo
+    pub fn from_wav(path: &Path) -> Result<(Self, hound::WavSpec), anyhow::Error> {
+        let (samples, spec) = audio_file::read_stereo(path)?;
+        Ok((Self::new(samples, spec.sample_rate), spec))
+    }
+}
+
+impl Source for BufferSource {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn read(&mut self, output: &mut Vec<f32>, max_frames: usize) -> Result<bool, anyhow::Error> {
+        let end = (self.position + max_frames * CHANNELS).min(self.samples.len());
+        let read_any = end > self.position;
+        output.extend_from_slice(&self.samples[self.position..end]);
+        self.position = end;
+        Ok(read_any)
+    }
+}
+
+// Records to a WAV file, in any format `audio_file::write_samples` can write
+pub struct WavSink {
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, spec: hound::WavSpec) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            writer: Some(hound::WavWriter::create(path, spec)?),
+        })
+    }
+}
+
+impl Sink for WavSink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+        match &mut self.writer {
+            Some(writer) => audio_file::write_samples(writer, samples),
+            None => Err(anyhow::Error::msg("WAV file already finished")),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+impl Stage for Resampler {
+    fn output_rate(&self, _input_rate: u32) -> u32 {
+        self.to_rate()
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        Resampler::process(self, input, output);
+        Ok(())
+    }
+
+    fn flush(&mut self, output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        Resampler::flush(self, output);
+        Ok(())
+    }
+}
+
+// The Opus encoder, network channel and decoder as one stage. Audio has to come
+// in at the codec rate, and is cut into frames whatever size the blocks are.
+pub struct CodecStage {
+    pipeline: Pipeline,
+    accumulator: FrameAccumulator,
+}
+
+impl CodecStage {
+    pub fn new(pipeline: Pipeline) -> Self {
+        let frame_len = pipeline.frame_size() * CHANNELS;
+        Self {
+            pipeline,
+            accumulator: FrameAccumulator::new(frame_len),
+        }
+    }
+
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+}
+
+impl Stage for CodecStage {
+    fn output_rate(&self, _input_rate: u32) -> u32 {
+        self.pipeline.sample_rate()
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        for &sample in input {
+            self.accumulator.push(sample);
+        }
+        while let Some(frame) = self.accumulator.next_frame() {
+            self.pipeline.process(frame, output)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        // Zero-pad the last frame if the input didn't end on a frame boundary
+        let leftover = self.accumulator.pending();
+        if leftover > 0 {
+            let padding = self.pipeline.frame_size() * CHANNELS - leftover;
+            self.process(&vec![0.0; padding], output)?;
+        }
+        // Let the packets still in flight or buffered play out
+        self.pipeline.flush(output)
+    }
+
+    fn stats(&self) -> Option<String> {
+        Some(self.pipeline.stats().to_string())
+    }
+}
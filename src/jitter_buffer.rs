@@ -0,0 +1,279 @@
+use crate::network_simulator::Packet;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum JitterBufferMode {
+    // Every packet is played a fixed time after it was sent
+    Fixed {
+        depth_us: u64,
+    },
+    // The depth follows the measured transit time and jitter, within bounds
+    Adaptive {
+        min_depth_us: u64,
+        max_depth_us: u64,
+    },
+}
+
+// What the receiver has to play for the next frame
+#[derive(Debug)]
+pub enum Playout {
+    Packet(Vec<u8>),
+    // The packet was lost, or hadn't arrived by the time it was due
+    Missing,
+}
+
+// Frames to remember as missing, so a packet arriving this late still counts as late
+const MISSING_HISTORY: u64 = 1024;
+// Frames ahead of the one playing there's room for before the buffer has to grow
+const PACKET_CAPACITY: usize = 256;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JitterBufferStats {
+    pub played: u64,
+    pub missing: u64,
+    // Packets that showed up after their frame had already been given up on
+    pub late: u64,
+    // Extra copies of packets already received, which are thrown away
+    pub duplicates: u64,
+    // Packets that arrived after one with a higher sequence number
+    pub reordered: u64,
+}
+
+// Receive-side buffer that reorders packets by sequence number and decides when
+// each frame is played. Packets are expected to be sent one per frame starting at
+// time zero, so frame `n` is due at `n * frame_duration_us + depth_us`.
+pub struct JitterBuffer {
+    mode: JitterBufferMode,
+    frame_duration_us: u64,
+    depth_us: u64,
+    // Slot `n` holds the packet for frame `next_sequence + n`, if it's here
+    packets: VecDeque<Option<Packet>>,
+    next_sequence: u64,
+    highest_sequence: Option<u64>,
+    // Whether each of the last `MISSING_HISTORY` frames was played as missing, to
+    // tell a late packet from a duplicate of one that was already played. Indexed by
+    // sequence number modulo its length.
+    missing_history: Vec<bool>,
+    // Running estimates used by the adaptive mode, following RFC 3550's jitter estimator
+    mean_transit_us: f64,
+    jitter_us: f64,
+    last_transit_us: Option<f64>,
+    stats: JitterBufferStats,
+}
+
+impl JitterBuffer {
+    pub fn new(mode: JitterBufferMode, frame_duration_us: u64) -> Self {
+        let depth_us = match mode {
+            JitterBufferMode::Fixed { depth_us } => depth_us,
+            JitterBufferMode::Adaptive { min_depth_us, .. } => min_depth_us,
+        };
+        Self {
+            mode,
+            frame_duration_us,
+            depth_us,
+            packets: VecDeque::with_capacity(PACKET_CAPACITY),
+            next_sequence: 0,
+            highest_sequence: None,
+            missing_history: vec![false; MISSING_HISTORY as usize],
+            mean_transit_us: 0.0,
+            jitter_us: 0.0,
+            last_transit_us: None,
+            stats: JitterBufferStats::default(),
+        }
+    }
+
+    // Queues a packet for its frame. A packet that's late or a duplicate is handed
+    // back, so its payload can be reused.
+    pub fn push(&mut self, packet: Packet) -> Option<Packet> {
+        self.update_depth(&packet);
+
+        let sequence = packet.sequence;
+        if sequence < self.next_sequence {
+            let index = (sequence % MISSING_HISTORY) as usize;
+            if self.next_sequence - sequence <= MISSING_HISTORY && self.missing_history[index] {
+                self.missing_history[index] = false;
+                self.stats.late += 1;
+            } else {
+                self.stats.duplicates += 1;
+            }
+            return Some(packet);
+        }
+        let offset = (sequence - self.next_sequence) as usize;
+        if self.packets.len() <= offset {
+            self.packets.resize_with(offset + 1, || None);
+        }
+        if self.packets[offset].is_some() {
+            self.stats.duplicates += 1;
+            return Some(packet);
+        }
+
+        match self.highest_sequence {
+            Some(highest) if sequence < highest => self.stats.reordered += 1,
+            _ => self.highest_sequence = Some(sequence),
+        }
+        self.packets[offset] = Some(packet);
+        None
+    }
+
+    // Returns the next frame once it's due at `now_us`. May return several frames in
+    // a row if the buffer shrank, or none for a while if it grew.
+    pub fn pop(&mut self, now_us: u64) -> Option<Playout> {
+        let due_us = self.next_sequence * self.frame_duration_us + self.depth_us;
+        if now_us < due_us {
+            return None;
+        }
+
+        let (playout, missing) = match self.packets.pop_front().flatten() {
+            Some(packet) => {
+                self.stats.played += 1;
+                (Playout::Packet(packet.payload), false)
+            }
+            None => {
+                self.stats.missing += 1;
+                (Playout::Missing, true)
+            }
+        };
+        // Overwrites the frame `MISSING_HISTORY` back, which won't turn up any more
+        self.missing_history[(self.next_sequence % MISSING_HISTORY) as usize] = missing;
+        self.next_sequence += 1;
+        Some(playout)
+    }
+
+    // The packet for the next frame, if it's already here. After a missing frame this
+    // is where in-band FEC data for the lost frame can be found.
+    pub fn peek_next(&self) -> Option<&Packet> {
+        self.packets.front().and_then(Option::as_ref)
+    }
+
+    // Sequence number of the next frame to be played
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub fn depth_us(&self) -> u64 {
+        self.depth_us
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        self.stats
+    }
+
+    fn update_depth(&mut self, packet: &Packet) {
+        let JitterBufferMode::Adaptive {
+            min_depth_us,
+            max_depth_us,
+        } = self.mode
+        else {
+            return;
+        };
+
+        let transit_us = (packet.arrival_us - packet.sent_at_us) as f64;
+        match self.last_transit_us {
+            Some(last_transit_us) => {
+                let difference = (transit_us - last_transit_us).abs();
+                self.jitter_us += (difference - self.jitter_us) / 16.0;
+                self.mean_transit_us += (transit_us - self.mean_transit_us) / 16.0;
+            }
+            None => self.mean_transit_us = transit_us,
+        }
+        self.last_transit_us = Some(transit_us);
+
+        // Leave room for the typical transit time plus a few deviations of jitter
+        let target_us = self.mean_transit_us + 4.0 * self.jitter_us;
+        self.depth_us = (target_us as u64).clamp(min_depth_us, max_depth_us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_US: u64 = 20_000;
+
+    fn packet(sequence: u64, arrival_us: u64) -> Packet {
+        Packet {
+            sequence,
+            sent_at_us: sequence * FRAME_US,
+            arrival_us,
+            payload: vec![sequence as u8],
+        }
+    }
+
+    fn fixed(depth_us: u64) -> JitterBuffer {
+        JitterBuffer::new(JitterBufferMode::Fixed { depth_us }, FRAME_US)
+    }
+
+    fn played(playout: Option<Playout>) -> Option<u8> {
+        match playout {
+            Some(Playout::Packet(payload)) => Some(payload[0]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn waits_until_each_frame_is_due() {
+        let mut buffer = fixed(40_000);
+        assert!(buffer.push(packet(0, 10_000)).is_none());
+        assert!(buffer.pop(39_999).is_none());
+        assert_eq!(played(buffer.pop(40_000)), Some(0));
+        assert!(buffer.pop(40_000).is_none());
+    }
+
+    #[test]
+    fn plays_reordered_packets_in_sequence() {
+        let mut buffer = fixed(40_000);
+        buffer.push(packet(1, 25_000));
+        buffer.push(packet(0, 30_000));
+        assert_eq!(played(buffer.pop(40_000)), Some(0));
+        assert_eq!(played(buffer.pop(60_000)), Some(1));
+        assert_eq!(buffer.stats().reordered, 1);
+        assert_eq!(buffer.stats().played, 2);
+    }
+
+    #[test]
+    fn counts_late_packets_and_duplicates() {
+        let mut buffer = fixed(40_000);
+        assert!(matches!(buffer.pop(40_000), Some(Playout::Missing)));
+        buffer.push(packet(1, 45_000));
+        assert!(buffer.push(packet(1, 46_000)).is_some());
+        // Frame 0 was already given up on, so it's late the first time and a
+        // duplicate after that
+        assert!(buffer.push(packet(0, 50_000)).is_some());
+        assert!(buffer.push(packet(0, 51_000)).is_some());
+        assert_eq!(played(buffer.pop(60_000)), Some(1));
+
+        let stats = buffer.stats();
+        assert_eq!(stats.missing, 1);
+        assert_eq!(stats.late, 1);
+        assert_eq!(stats.duplicates, 2);
+    }
+
+    #[test]
+    fn peeks_at_the_next_packet() {
+        let mut buffer = fixed(0);
+        buffer.push(packet(1, 0));
+        assert!(matches!(buffer.pop(0), Some(Playout::Missing)));
+        assert_eq!(buffer.peek_next().map(|packet| packet.sequence), Some(1));
+        assert_eq!(buffer.next_sequence(), 1);
+    }
+
+    #[test]
+    fn adaptive_depth_follows_transit_time() {
+        let mut buffer = JitterBuffer::new(
+            JitterBufferMode::Adaptive {
+                min_depth_us: 20_000,
+                max_depth_us: 200_000,
+            },
+            FRAME_US,
+        );
+        assert_eq!(buffer.depth_us(), 20_000);
+        for sequence in 0..50 {
+            buffer.push(packet(sequence, sequence * FRAME_US + 60_000));
+        }
+        assert_eq!(buffer.depth_us(), 60_000);
+        buffer.push(packet(50, 50 * FRAME_US + 10_000_000));
+        assert_eq!(buffer.depth_us(), 200_000);
+    }
+}
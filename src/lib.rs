@@ -0,0 +1,30 @@
+// The glitch chain as a library, for the binaries in this crate and any other
+// tool that wants to embed it. `graph` strings the pieces together: a source,
+// stages such as resampling and the Opus codec with its network channel, and sinks.
+pub mod audio_file;
+pub mod automation;
+pub mod bottleneck;
+pub mod channel_mapper;
+#[cfg(feature = "clap-plugin")]
+mod clap_abi;
+#[cfg(feature = "clap-plugin")]
+pub mod clap_plugin;
+pub mod codec;
+pub mod corruption;
+pub mod devices;
+pub mod frame_accumulator;
+pub mod glitch_effect;
+pub mod graph;
+pub mod jitter_buffer;
+pub mod loss_model;
+pub mod loss_strategy;
+pub mod mixer;
+pub mod network_channel;
+pub mod network_simulator;
+pub mod opus_encoder;
+pub mod pipeline;
+pub mod preset;
+pub mod realtime;
+pub mod resampler;
+pub mod step_sequencer;
+pub mod trace;
@@ -0,0 +1,174 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use rust_opus_test::audio_file;
+use rust_opus_test::devices;
+use rust_opus_test::frame_accumulator::FrameAccumulator;
+use rust_opus_test::pipeline::{Pipeline, CHANNELS};
+use rust_opus_test::realtime::{CallbackTap, MonitorTap, Processor, Sinks, Workers};
+use rust_opus_test::resampler::Resampler;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+pub struct LiveOptions<'a> {
+    // How much processed audio may wait for the output before it's skipped
+    pub max_latency_ms: f64,
+    pub record_dry: Option<&'a Path>,
+    pub record_wet: Option<&'a Path>,
+}
+
+// Runs an input device through the pipeline to an output device until Enter is
+// pressed. The delay through the chain is the network and jitter buffer delay
+// plus at most `max_latency_ms` of buffering in front of the output.
+pub fn run(
+    input_device: &cpal::Device,
+    output_device: &cpal::Device,
+    output_config: cpal::SupportedStreamConfig,
+    pipeline: Pipeline,
+    frame_size: usize,
+    options: LiveOptions,
+) -> Result<(), anyhow::Error> {
+    let input_config = devices::input_config(input_device)?;
+    let input_rate = input_config.sample_rate().0;
+    let input_channels = input_config.channels() as usize;
+    let output_rate = output_config.sample_rate().0;
+    println!(
+        "Input device: {} ({} channels at {} Hz)",
+        devices::device_name(input_device),
+        input_channels,
+        input_rate
+    );
+
+    let codec_rate = pipeline.sample_rate();
+    let processor = Processor {
+        input_channels,
+        input_resampler: Resampler::new(input_rate, codec_rate, CHANNELS),
+        accumulator: FrameAccumulator::new(frame_size * CHANNELS),
+        pipeline,
+        output_resampler: Resampler::new(codec_rate, output_rate, CHANNELS),
+    };
+
+    let max_buffered_frames = (options.max_latency_ms / 1000.0 * output_rate as f64) as usize;
+    let (monitor, monitor_tap) = MonitorTap::new(
+        output_rate as usize * CHANNELS * 2,
+        max_buffered_frames * CHANNELS,
+    );
+    let underruns = monitor_tap.underruns();
+    let create_recording = |path: Option<&Path>, sample_rate| {
+        path.map(|path| hound::WavWriter::create(path, audio_file::float_spec(sample_rate)))
+            .transpose()
+    };
+    let sinks = Sinks {
+        wet: create_recording(options.record_wet, output_rate)?,
+        dry: create_recording(options.record_dry, input_rate)?,
+        monitor: Some(monitor),
+    };
+    let (tap, workers) = Workers::spawn(processor, sinks, input_rate as usize * input_channels * 2);
+
+    let output_stream = build_monitor_stream(output_device, &output_config, monitor_tap)?;
+    let input_stream = build_capture_stream(input_device, &input_config, tap)?;
+    output_stream.play()?;
+    input_stream.play()?;
+
+    println!("Live, press Enter to stop...");
+    std::io::stdin().read_line(&mut String::new())?;
+
+    // Stopping the input lets the workers finish and close the recordings
+    drop(input_stream);
+    let (processor, overruns) = workers.join()?;
+    drop(output_stream);
+
+    println!("{}", processor.pipeline.stats());
+    if overruns > 0 {
+        println!("{overruns} times audio was dropped because processing fell behind");
+    }
+    let underruns = underruns.load(Ordering::Relaxed);
+    if underruns > 0 {
+        println!("{underruns} output callbacks ran short of audio");
+    }
+    for path in [options.record_dry, options.record_wet]
+        .into_iter()
+        .flatten()
+    {
+        println!("Recorded {}", path.display());
+    }
+    Ok(())
+}
+
+fn build_capture_stream(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    tap: CallbackTap,
+) -> Result<cpal::Stream, anyhow::Error> {
+    match config.sample_format() {
+        SampleFormat::I8 => capture_stream::<i8>(device, config, tap),
+        SampleFormat::I16 => capture_stream::<i16>(device, config, tap),
+        SampleFormat::I32 => capture_stream::<i32>(device, config, tap),
+        SampleFormat::I64 => capture_stream::<i64>(device, config, tap),
+        SampleFormat::U8 => capture_stream::<u8>(device, config, tap),
+        SampleFormat::U16 => capture_stream::<u16>(device, config, tap),
+        SampleFormat::U32 => capture_stream::<u32>(device, config, tap),
+        SampleFormat::U64 => capture_stream::<u64>(device, config, tap),
+        SampleFormat::F32 => capture_stream::<f32>(device, config, tap),
+        SampleFormat::F64 => capture_stream::<f64>(device, config, tap),
+        format => Err(anyhow::Error::msg(format!(
+            "Unsupported sample format '{format}'"
+        ))),
+    }
+}
+
+fn capture_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    mut tap: CallbackTap,
+) -> Result<cpal::Stream, anyhow::Error>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let stream = device.build_input_stream(
+        &config.config(),
+        move |data: &[T], _: &_| tap.push(data),
+        |err| eprintln!("an error occurred on the input stream: {}", err),
+        None,
+    )?;
+    Ok(stream)
+}
+
+fn build_monitor_stream(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    tap: MonitorTap,
+) -> Result<cpal::Stream, anyhow::Error> {
+    match config.sample_format() {
+        SampleFormat::I8 => monitor_stream::<i8>(device, config, tap),
+        SampleFormat::I16 => monitor_stream::<i16>(device, config, tap),
+        SampleFormat::I32 => monitor_stream::<i32>(device, config, tap),
+        SampleFormat::I64 => monitor_stream::<i64>(device, config, tap),
+        SampleFormat::U8 => monitor_stream::<u8>(device, config, tap),
+        SampleFormat::U16 => monitor_stream::<u16>(device, config, tap),
+        SampleFormat::U32 => monitor_stream::<u32>(device, config, tap),
+        SampleFormat::U64 => monitor_stream::<u64>(device, config, tap),
+        SampleFormat::F32 => monitor_stream::<f32>(device, config, tap),
+        SampleFormat::F64 => monitor_stream::<f64>(device, config, tap),
+        format => Err(anyhow::Error::msg(format!(
+            "Unsupported sample format '{format}'"
+        ))),
+    }
+}
+
+fn monitor_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    mut tap: MonitorTap,
+) -> Result<cpal::Stream, anyhow::Error>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let stream = device.build_output_stream(
+        &config.config(),
+        move |data: &mut [T], _: &_| tap.pull(data),
+        |err| eprintln!("an error occurred on the output stream: {}", err),
+        None,
+    )?;
+    Ok(stream)
+}
@@ -0,0 +1,245 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Decides which packets get dropped on their way through the network
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "model", rename_all = "kebab-case")]
+pub enum LossModel {
+    // Every packet is dropped independently with the same probability
+    Bernoulli { loss_probability: f32 },
+    // Two-state Markov chain alternating between good and bad periods, so losses
+    // cluster into bursts like they do on a real congested link
+    GilbertElliott(GilbertElliott),
+}
+
+impl LossModel {
+    pub fn should_drop<R: Rng>(&mut self, rng: &mut R) -> bool {
+        match self {
+            LossModel::Bernoulli { loss_probability } => rng.gen::<f32>() < *loss_probability,
+            LossModel::GilbertElliott(model) => model.should_drop(rng),
+        }
+    }
+
+    // Rejects probabilities outside 0 to 1 and chains that would never recover
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        match self {
+            LossModel::Bernoulli { loss_probability } => {
+                check_probability("loss_probability", *loss_probability)
+            }
+            LossModel::GilbertElliott(model) => model.validate(),
+        }
+    }
+
+    pub fn average_loss_probability(&self) -> f32 {
+        match self {
+            LossModel::Bernoulli { loss_probability } => *loss_probability,
+            LossModel::GilbertElliott(model) => model.average_loss_probability(),
+        }
+    }
+
+    // Retunes the model to lose `loss_probability` of packets on average, keeping
+    // whatever burstiness it has
+    pub fn set_average_loss_probability(&mut self, loss_probability: f32) {
+        match self {
+            LossModel::Bernoulli {
+                loss_probability: current,
+            } => *current = loss_probability,
+            LossModel::GilbertElliott(model) => {
+                model.set_average_loss_probability(loss_probability)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GilbertElliott {
+    // Per-packet chance of moving from the good state into the bad state
+    pub good_to_bad_probability: f32,
+    // Per-packet chance of recovering from the bad state, 1 / mean burst length
+    pub bad_to_good_probability: f32,
+    pub good_loss_probability: f32,
+    pub bad_loss_probability: f32,
+    #[serde(skip)]
+    in_bad_state: bool,
+}
+
+impl GilbertElliott {
+    pub fn new(
+        good_to_bad_probability: f32,
+        bad_to_good_probability: f32,
+        good_loss_probability: f32,
+        bad_loss_probability: f32,
+    ) -> Self {
+        Self {
+            good_to_bad_probability,
+            bad_to_good_probability,
+            good_loss_probability,
+            bad_loss_probability,
+            in_bad_state: false,
+        }
+    }
+
+    // Classic Gilbert model where the good state never loses and the bad state always
+    // does, tuned so that on average `average_loss` of packets are lost in bursts
+    // lasting `mean_burst_length` packets
+    pub fn from_average_loss(average_loss: f32, mean_burst_length: f32) -> Self {
+        let bad_to_good_probability = 1.0 / mean_burst_length.max(1.0);
+        let average_loss = average_loss.clamp(0.0, 0.99);
+        let good_to_bad_probability =
+            (average_loss * bad_to_good_probability / (1.0 - average_loss)).min(1.0);
+        Self::new(good_to_bad_probability, bad_to_good_probability, 0.0, 1.0)
+    }
+
+    // Infinite if the bad state is never left
+    pub fn mean_burst_length(&self) -> f32 {
+        if self.bad_to_good_probability <= 0.0 {
+            return f32::INFINITY;
+        }
+        1.0 / self.bad_to_good_probability
+    }
+
+    // Rejects probabilities outside 0 to 1, and parameters that would leave the
+    // chain stuck in the bad state
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        check_probability("good_to_bad_probability", self.good_to_bad_probability)?;
+        check_probability("bad_to_good_probability", self.bad_to_good_probability)?;
+        check_probability("good_loss_probability", self.good_loss_probability)?;
+        check_probability("bad_loss_probability", self.bad_loss_probability)?;
+        if self.bad_to_good_probability <= 0.0 {
+            return Err(anyhow::Error::msg(format!(
+                "bad_to_good_probability must be above 0, got {}",
+                self.bad_to_good_probability
+            )));
+        }
+        Ok(())
+    }
+
+    // Long-run fraction of packets lost, handy for sanity checking parameters
+    pub fn average_loss_probability(&self) -> f32 {
+        let transitions = self.good_to_bad_probability + self.bad_to_good_probability;
+        if transitions <= 0.0 {
+            return self.good_loss_probability;
+        }
+        let bad_share = self.good_to_bad_probability / transitions;
+        (1.0 - bad_share) * self.good_loss_probability + bad_share * self.bad_loss_probability
+    }
+
+    // Changes how often the bad state is entered, keeping the burst length and the
+    // loss within each state. Can't go below the good state's own loss rate.
+    fn set_average_loss_probability(&mut self, average_loss: f32) {
+        let loss_range = self.bad_loss_probability - self.good_loss_probability;
+        if loss_range <= 0.0 {
+            return;
+        }
+        let bad_share = ((average_loss - self.good_loss_probability) / loss_range).clamp(0.0, 0.99);
+        self.good_to_bad_probability =
+            (bad_share * self.bad_to_good_probability / (1.0 - bad_share)).min(1.0);
+    }
+
+    fn should_drop<R: Rng>(&mut self, rng: &mut R) -> bool {
+        // Step the chain first, then draw the loss from the state we landed in
+        let transition_probability = if self.in_bad_state {
+            self.bad_to_good_probability
+        } else {
+            self.good_to_bad_probability
+        };
+        if rng.gen::<f32>() < transition_probability {
+            self.in_bad_state = !self.in_bad_state;
+        }
+
+        let loss_probability = if self.in_bad_state {
+            self.bad_loss_probability
+        } else {
+            self.good_loss_probability
+        };
+        rng.gen::<f32>() < loss_probability
+    }
+}
+
+fn check_probability(name: &str, probability: f32) -> Result<(), anyhow::Error> {
+    if (0.0..=1.0).contains(&probability) {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "{name} must be between 0 and 1, got {probability}"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Fraction of `packets` packets dropped, and the mean length of the runs of drops
+    fn measure(model: &mut LossModel, packets: usize) -> (f32, f32) {
+        let mut rng = StdRng::seed_from_u64(7);
+        let (mut lost, mut bursts, mut in_burst) = (0, 0, false);
+        for _ in 0..packets {
+            let drop = model.should_drop(&mut rng);
+            if drop {
+                lost += 1;
+                if !in_burst {
+                    bursts += 1;
+                }
+            }
+            in_burst = drop;
+        }
+        (
+            lost as f32 / packets as f32,
+            lost as f32 / bursts.max(1) as f32,
+        )
+    }
+
+    #[test]
+    fn bernoulli_loses_its_probability() {
+        let mut model = LossModel::Bernoulli {
+            loss_probability: 0.2,
+        };
+        let (loss, _) = measure(&mut model, 100_000);
+        assert!((loss - 0.2).abs() < 0.01, "lost {loss}");
+    }
+
+    #[test]
+    fn gilbert_elliott_matches_loss_and_burst_length() {
+        let model = GilbertElliott::from_average_loss(0.1, 4.0);
+        assert!((model.average_loss_probability() - 0.1).abs() < 1e-6);
+        assert!((model.mean_burst_length() - 4.0).abs() < 1e-6);
+
+        let (loss, burst_length) = measure(&mut LossModel::GilbertElliott(model), 200_000);
+        assert!((loss - 0.1).abs() < 0.01, "lost {loss}");
+        assert!((burst_length - 4.0).abs() < 0.3, "bursts of {burst_length}");
+    }
+
+    #[test]
+    fn retuning_keeps_the_burst_length() {
+        let mut model = LossModel::GilbertElliott(GilbertElliott::from_average_loss(0.1, 5.0));
+        model.set_average_loss_probability(0.3);
+        assert!((model.average_loss_probability() - 0.3).abs() < 1e-6);
+        let LossModel::GilbertElliott(model) = model else {
+            unreachable!()
+        };
+        assert!((model.mean_burst_length() - 5.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stuck_bad_state_is_rejected() {
+        let model = GilbertElliott::new(0.1, 0.0, 0.0, 1.0);
+        assert_eq!(model.mean_burst_length(), f32::INFINITY);
+        assert!(model.validate().is_err());
+        assert!(GilbertElliott::from_average_loss(0.1, 3.0)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn probabilities_out_of_range_are_rejected() {
+        let bernoulli = |loss_probability| LossModel::Bernoulli { loss_probability };
+        assert!(bernoulli(0.5).validate().is_ok());
+        assert!(bernoulli(1.5).validate().is_err());
+        assert!(bernoulli(f32::NAN).validate().is_err());
+        assert!(GilbertElliott::new(-0.1, 0.5, 0.0, 1.0).validate().is_err());
+        assert!(GilbertElliott::new(0.1, 0.5, 0.0, 2.0).validate().is_err());
+    }
+}
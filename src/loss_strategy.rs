@@ -0,0 +1,409 @@
+use crate::pipeline::CHANNELS;
+use opus::Decoder;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+// Fills in the frames the receiver has nothing for, once FEC has had its go. Any
+// type implementing this can be handed to `Pipeline::with_loss_handler`, so a
+// strategy doesn't have to be one of `LossStrategy`'s.
+pub trait LossHandler: Send {
+    // Sees every frame decoded from a packet that arrived, along with the packet
+    fn received(&mut self, payload: &[u8], frame: &[f32]) {
+        let _ = (payload, frame);
+    }
+
+    // Fills all of `frame` in for a missing one. `run` counts the frames missing in
+    // a row, starting at 1. The decoder is there for Opus concealment.
+    fn conceal(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: &mut [f32],
+        run: u32,
+    ) -> Result<(), anyhow::Error>;
+}
+
+// The built-in ways of filling in for lost packets, from realistic to stylized.
+// Anything with nothing to go on yet, at the very start, falls back to Opus
+// concealment.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "strategy", rename_all = "kebab-case")]
+pub enum LossStrategy {
+    // Opus packet loss concealment, which stretches out and fades the last sound
+    // like a real call does
+    #[default]
+    Plc,
+    // Nothing at all
+    Silence,
+    // Decodes the last packet that arrived again, for up to `times` frames in a
+    // row before handing over to Opus concealment
+    Repeat {
+        times: u32,
+    },
+    // Loops the last `frames` frames that arrived for as long as the loss lasts
+    Hold {
+        frames: u32,
+    },
+    // Plays the last frame that arrived backwards, then forwards, and so on
+    Reverse,
+    // Fades the last frame that arrived out as Opus concealment fades in
+    Crossfade,
+}
+
+impl LossStrategy {
+    pub fn build(&self) -> Box<dyn LossHandler> {
+        match *self {
+            LossStrategy::Plc => Box::new(OpusConcealment),
+            LossStrategy::Silence => Box::new(Silence),
+            LossStrategy::Repeat { times } => Box::new(RepeatPacket {
+                times,
+                last_packet: None,
+            }),
+            LossStrategy::Hold { frames } => Box::new(HoldFrames {
+                frames: frames.max(1) as usize,
+                history: VecDeque::new(),
+            }),
+            LossStrategy::Reverse => Box::new(Reverse {
+                last_frame: Vec::new(),
+            }),
+            LossStrategy::Crossfade => Box::new(Crossfade {
+                last_frame: Vec::new(),
+            }),
+        }
+    }
+}
+
+// "plc", "silence", "repeat[:<times>]", "hold[:<frames>]", "reverse" or "crossfade"
+impl FromStr for LossStrategy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, count) = match text.split_once(':') {
+            Some((name, count)) => {
+                // 0 would quietly leave plain Opus concealment
+                let count: u32 = count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| format!("invalid frame count '{count}', expected 1 or more"))?;
+                (name, Some(count))
+            }
+            None => (text, None),
+        };
+        match (name, count) {
+            ("plc", None) => Ok(LossStrategy::Plc),
+            ("silence", None) => Ok(LossStrategy::Silence),
+            ("repeat", times) => Ok(LossStrategy::Repeat {
+                times: times.unwrap_or(2),
+            }),
+            ("hold", frames) => Ok(LossStrategy::Hold {
+                frames: frames.unwrap_or(1),
+            }),
+            ("reverse", None) => Ok(LossStrategy::Reverse),
+            ("crossfade", None) => Ok(LossStrategy::Crossfade),
+            _ => Err(format!(
+                "unknown loss strategy '{text}', expected plc, silence, repeat[:<times>], \
+                 hold[:<frames>], reverse or crossfade"
+            )),
+        }
+    }
+}
+
+struct OpusConcealment;
+
+impl LossHandler for OpusConcealment {
+    fn conceal(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: &mut [f32],
+        _run: u32,
+    ) -> Result<(), anyhow::Error> {
+        opus_concealment(decoder, frame)
+    }
+}
+
+struct Silence;
+
+impl LossHandler for Silence {
+    fn conceal(
+        &mut self,
+        _decoder: &mut Decoder,
+        frame: &mut [f32],
+        _run: u32,
+    ) -> Result<(), anyhow::Error> {
+        frame.fill(0.0);
+        Ok(())
+    }
+}
+
+struct RepeatPacket {
+    times: u32,
+    last_packet: Option<Vec<u8>>,
+}
+
+impl LossHandler for RepeatPacket {
+    fn received(&mut self, payload: &[u8], _frame: &[f32]) {
+        let last_packet = self.last_packet.get_or_insert_with(Vec::new);
+        last_packet.clear();
+        last_packet.extend_from_slice(payload);
+    }
+
+    fn conceal(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: &mut [f32],
+        run: u32,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(last_packet) = self.last_packet.as_ref().filter(|_| run <= self.times) {
+            // A packet that doesn't decode to exactly one frame is left to Opus instead
+            let frame_size = frame.len() / CHANNELS;
+            if let Ok(decoded_len) = decoder.decode_float(last_packet, frame, false) {
+                if decoded_len == frame_size {
+                    return Ok(());
+                }
+            }
+        }
+        opus_concealment(decoder, frame)
+    }
+}
+
+struct HoldFrames {
+    frames: usize,
+    // The last `frames` frames that arrived, oldest first
+    history: VecDeque<Vec<f32>>,
+}
+
+impl LossHandler for HoldFrames {
+    fn received(&mut self, _payload: &[u8], frame: &[f32]) {
+        let mut held = if self.history.len() < self.frames {
+            Vec::new()
+        } else {
+            self.history.pop_front().unwrap()
+        };
+        held.clear();
+        held.extend_from_slice(frame);
+        self.history.push_back(held);
+    }
+
+    fn conceal(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: &mut [f32],
+        run: u32,
+    ) -> Result<(), anyhow::Error> {
+        if self.history.is_empty() {
+            return opus_concealment(decoder, frame);
+        }
+        let held = &self.history[(run as usize - 1) % self.history.len()];
+        copy_looped(held, frame);
+        Ok(())
+    }
+}
+
+struct Reverse {
+    last_frame: Vec<f32>,
+}
+
+impl LossHandler for Reverse {
+    fn received(&mut self, _payload: &[u8], frame: &[f32]) {
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(frame);
+    }
+
+    fn conceal(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: &mut [f32],
+        run: u32,
+    ) -> Result<(), anyhow::Error> {
+        if self.last_frame.is_empty() {
+            return opus_concealment(decoder, frame);
+        }
+        copy_looped(&self.last_frame, frame);
+        // Backwards first, starting from where the last frame left off
+        if run % 2 == 1 {
+            reverse_frames(frame);
+        }
+        Ok(())
+    }
+}
+
+struct Crossfade {
+    last_frame: Vec<f32>,
+}
+
+impl LossHandler for Crossfade {
+    fn received(&mut self, _payload: &[u8], frame: &[f32]) {
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(frame);
+    }
+
+    fn conceal(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: &mut [f32],
+        run: u32,
+    ) -> Result<(), anyhow::Error> {
+        opus_concealment(decoder, frame)?;
+        // Only the first missing frame fades, the rest is left to Opus
+        if run > 1 || self.last_frame.is_empty() {
+            return Ok(());
+        }
+        let frames = frame.len() / CHANNELS;
+        for (index, samples) in frame.chunks_exact_mut(CHANNELS).enumerate() {
+            let fade_in = index as f32 / frames as f32;
+            for (channel, sample) in samples.iter_mut().enumerate() {
+                let held = self.last_frame[(index * CHANNELS + channel) % self.last_frame.len()];
+                *sample = held * (1.0 - fade_in) + *sample * fade_in;
+            }
+        }
+        Ok(())
+    }
+}
+
+// An empty packet asks the decoder for packet loss concealment. The decoder
+// produces exactly as many samples as the buffer it's given.
+fn opus_concealment(decoder: &mut Decoder, frame: &mut [f32]) -> Result<(), anyhow::Error> {
+    decoder.decode_float(&[], frame, false)?;
+    Ok(())
+}
+
+// Fills `frame` with `source`, starting over from its beginning if it's shorter
+fn copy_looped(source: &[f32], frame: &mut [f32]) {
+    for (sample, &held) in frame.iter_mut().zip(source.iter().cycle()) {
+        *sample = held;
+    }
+}
+
+// Reverses the order of the interleaved stereo frames, keeping each one's channels
+fn reverse_frames(samples: &mut [f32]) {
+    let frames = samples.len() / CHANNELS;
+    for index in 0..frames / 2 {
+        for channel in 0..CHANNELS {
+            samples.swap(
+                index * CHANNELS + channel,
+                (frames - 1 - index) * CHANNELS + channel,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecConfig;
+
+    // 20 ms at 48 kHz
+    const FRAME_SIZE: usize = 960;
+
+    fn new_decoder() -> Decoder {
+        CodecConfig::default().build_decoder().unwrap()
+    }
+
+    // Interleaved stereo frame with every sample of frame `index` set to `index`
+    // plus `offset`, the right channel negated
+    fn ramp(offset: f32) -> Vec<f32> {
+        (0..FRAME_SIZE)
+            .flat_map(|index| [index as f32 + offset, -(index as f32 + offset)])
+            .collect()
+    }
+
+    fn conceal(handler: &mut dyn LossHandler, decoder: &mut Decoder, run: u32) -> Vec<f32> {
+        let mut frame = vec![0.0; FRAME_SIZE * CHANNELS];
+        handler.conceal(decoder, &mut frame, run).unwrap();
+        frame
+    }
+
+    #[test]
+    fn parses_strategies() {
+        assert_eq!("plc".parse(), Ok(LossStrategy::Plc));
+        assert_eq!("repeat".parse(), Ok(LossStrategy::Repeat { times: 2 }));
+        assert_eq!("hold:4".parse(), Ok(LossStrategy::Hold { frames: 4 }));
+        assert!("reverse:2".parse::<LossStrategy>().is_err());
+        assert!("stutter".parse::<LossStrategy>().is_err());
+    }
+
+    #[test]
+    fn rejects_counts_of_zero() {
+        assert!("repeat:0".parse::<LossStrategy>().is_err());
+        assert!("hold:0".parse::<LossStrategy>().is_err());
+    }
+
+    #[test]
+    fn repeat_decodes_the_last_packet_again() {
+        let input: Vec<f32> = (0..FRAME_SIZE * CHANNELS)
+            .map(|index| (index as f32 * 0.05).sin() * 0.5)
+            .collect();
+        let mut packet = vec![0; 4000];
+        let len = CodecConfig::default()
+            .build_encoder()
+            .unwrap()
+            .encode_float(&input, &mut packet)
+            .unwrap();
+        packet.truncate(len);
+
+        let mut handler = LossStrategy::Repeat { times: 1 }.build();
+        handler.received(&packet, &input);
+        let mut decoder = new_decoder();
+        let repeated = conceal(handler.as_mut(), &mut decoder, 1);
+        let concealed = conceal(handler.as_mut(), &mut decoder, 2);
+
+        // The same as decoding it directly, then Opus concealment once `times` runs out
+        let mut reference = new_decoder();
+        let mut expected = vec![0.0; FRAME_SIZE * CHANNELS];
+        reference
+            .decode_float(&packet, &mut expected, false)
+            .unwrap();
+        assert_eq!(repeated, expected);
+        opus_concealment(&mut reference, &mut expected).unwrap();
+        assert_eq!(concealed, expected);
+    }
+
+    #[test]
+    fn hold_loops_the_last_frames() {
+        let mut handler = LossStrategy::Hold { frames: 2 }.build();
+        for offset in [1.0, 2.0, 3.0] {
+            handler.received(&[], &ramp(offset));
+        }
+        let mut decoder = new_decoder();
+        assert_eq!(conceal(handler.as_mut(), &mut decoder, 1), ramp(2.0));
+        assert_eq!(conceal(handler.as_mut(), &mut decoder, 2), ramp(3.0));
+        assert_eq!(conceal(handler.as_mut(), &mut decoder, 3), ramp(2.0));
+    }
+
+    #[test]
+    fn reverse_alternates_direction() {
+        let mut handler = LossStrategy::Reverse.build();
+        handler.received(&[], &ramp(0.0));
+        let mut decoder = new_decoder();
+
+        let backwards = conceal(handler.as_mut(), &mut decoder, 1);
+        let last = (FRAME_SIZE - 1) as f32;
+        assert_eq!(&backwards[..4], &[last, -last, last - 1.0, -(last - 1.0)]);
+        assert_eq!(&backwards[backwards.len() - 2..], &[0.0, -0.0]);
+        assert_eq!(conceal(handler.as_mut(), &mut decoder, 2), ramp(0.0));
+    }
+
+    #[test]
+    fn crossfade_fades_into_concealment() {
+        let mut handler = LossStrategy::Crossfade.build();
+        let last_frame = vec![1.0; FRAME_SIZE * CHANNELS];
+        handler.received(&[], &last_frame);
+        let mut decoder = new_decoder();
+        let faded = conceal(handler.as_mut(), &mut decoder, 1);
+        let second = conceal(handler.as_mut(), &mut decoder, 2);
+
+        let mut reference = new_decoder();
+        let mut concealed = vec![0.0; FRAME_SIZE * CHANNELS];
+        opus_concealment(&mut reference, &mut concealed).unwrap();
+        for (index, (&sample, &plc)) in faded.iter().zip(&concealed).enumerate() {
+            let fade_in = (index / CHANNELS) as f32 / FRAME_SIZE as f32;
+            let expected = 1.0 - fade_in + plc * fade_in;
+            assert!((sample - expected).abs() < 1e-6, "sample {index}");
+        }
+        assert_eq!(faded[0], 1.0);
+        // Only the first missing frame fades
+        opus_concealment(&mut reference, &mut concealed).unwrap();
+        assert_eq!(second, concealed);
+    }
+}
@@ -1,123 +1,166 @@
-mod network_simulator;
-
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{FromSample, Sample, SampleFormat, SampleRate};
-use network_simulator::NetworkSimulator;
-use opus::{Application, Decoder, Encoder};
-use std::fs::File;
-use std::io::BufWriter;
-use std::sync::{Arc, Mutex};
+mod cli;
+mod live;
+mod offline;
+
+use clap::Parser;
+use cli::Args;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use rust_opus_test::audio_file;
+use rust_opus_test::devices::{self, Direction};
+use rust_opus_test::frame_accumulator::FrameAccumulator;
+use rust_opus_test::network_channel::NetworkChannel;
+use rust_opus_test::pipeline::{Pipeline, CHANNELS};
+use rust_opus_test::preset::{builtin_presets, Preset};
+use rust_opus_test::realtime::{CallbackTap, Processor, Sinks, Workers};
+use rust_opus_test::resampler::Resampler;
 
 fn main() -> Result<(), anyhow::Error> {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .expect("no output device available");
-
-    // Set up input WAV file
-    let input_file = hound::WavReader::open("input.wav")?;
-    let input_spec = input_file.spec();
-    let duration_seconds = input_file.duration() as f32 / input_spec.sample_rate as f32;
-    println!("Input WAV spec: {:?}", input_spec);
+    let args = Args::parse();
 
-    // Set the desired sample rate
-    let desired_sample_rate = SampleRate(48000);
+    if args.list_devices {
+        devices::list();
+        return Ok(());
+    }
 
-    // Get supported config from device
-    let supported_config = device
-        .supported_output_configs()?
-        .find(|config| {
-            config.channels() == 2
-                && config.min_sample_rate() <= desired_sample_rate
-                && config.max_sample_rate() >= desired_sample_rate
-        })
-        .expect("no supported config")
-        .with_sample_rate(desired_sample_rate);
+    if args.list_presets {
+        for preset in builtin_presets() {
+            println!("{:<14} {}", preset.name, preset.description);
+        }
+        return Ok(());
+    }
 
-    // Prepare the output wav file
-    const PATH: &str = "output_recording.wav";
-    let spec = wav_file_spec_from_config(&supported_config);
-    let writer = hound::WavWriter::create(PATH, spec)?;
-    let writer = Arc::new(Mutex::new(Some(writer)));
-    let writer_clone = writer.clone();
+    // Start from the chosen preset and layer any command line overrides on top
+    let mut preset = match &args.preset {
+        Some(name_or_path) => Preset::load(name_or_path)?,
+        None => Preset::default(),
+    };
+    args.apply_to(&mut preset);
+    println!("Using preset: {}", preset.name);
+    if let Some(path) = &args.save_preset {
+        preset.save(path)?;
+        println!("Saved preset to {}", path.display());
+    }
 
     // Initialize Opus encoder and decoder
-    let mut encoder = Encoder::new(48000, opus::Channels::Stereo, Application::Voip)?;
-    let mut decoder = Decoder::new(48000, opus::Channels::Stereo)?;
+    let codec = &preset.codec;
+    let encoder = codec.build_encoder()?;
+    let decoder = codec.build_decoder()?;
+    let frame_duration = codec.frame_ms;
+    let codec_rate = codec.sample_rate;
+    let frame_size = frame_duration.frame_size(codec_rate);
+
+    // Set up network simulator and the receive side jitter buffer
+    let network = preset.network.build_network()?;
+    match network.trace() {
+        Some(trace) => {
+            if let Some(ssrc) = trace.ssrc() {
+                println!("Replaying RTP stream {ssrc:#010x}");
+            }
+            println!(
+                "Replaying a trace of {} packets, {:.1}% lost",
+                trace.packet_count(),
+                trace.loss_probability() * 100.0
+            )
+        }
+        None => println!("Network seed: {}", network.seed()),
+    }
+    let jitter_buffer = preset
+        .network
+        .build_jitter_buffer(frame_duration.duration_us());
 
-    // Set up network simulator
-    let network = NetworkSimulator::new(0.5, 10, 5);
+    let expected_loss = match network.trace() {
+        Some(trace) => trace.loss_probability(),
+        None => network.loss_model.average_loss_probability(),
+    };
+    let expected_loss_percent = (expected_loss * 100.0) as i32;
+    let mut pipeline = Pipeline::new(
+        encoder,
+        decoder,
+        NetworkChannel::new(network, jitter_buffer),
+        frame_duration,
+        codec_rate,
+    )
+    .with_loss_handler(codec.loss_strategy.build());
+    if codec.fec {
+        pipeline = pipeline.with_fec(expected_loss_percent)?;
+    }
+    if !preset.mix.is_bypassed() {
+        pipeline = pipeline.with_mixer(preset.mix.build_mixer(codec_rate))?;
+    }
 
-    println!("Begin processing...");
+    if args.offline {
+        return offline::render(&args.input, &args.output, args.output_rate, pipeline);
+    }
 
-    let err_fn = move |err| {
-        eprintln!("an error occurred on stream: {}", err);
+    let host = devices::host(args.host.as_deref())?;
+    println!("Host: {}", host.id().name());
+    let device = devices::select(&host, Direction::Output, &args.device)?;
+    println!("Output device: {}", devices::device_name(&device));
+
+    let supported_config = devices::stereo_output_config(&device)?;
+    let device_rate = supported_config.sample_rate().0;
+    println!("Device sample rate: {} Hz", device_rate);
+
+    if args.live {
+        let input_device = devices::select(&host, Direction::Input, &args.input_device)?;
+        let options = live::LiveOptions {
+            max_latency_ms: args.max_latency,
+            record_dry: args.record_dry.as_deref(),
+            record_wet: args.record_wet.as_deref(),
+        };
+        return live::run(
+            &input_device,
+            &device,
+            supported_config,
+            pipeline,
+            frame_size,
+            options,
+        );
+    }
+
+    // Set up input WAV file, converted to what the device plays
+    let (samples, input_spec) = audio_file::read_stereo(&args.input)?;
+    println!("Input WAV spec: {:?}", input_spec);
+    let samples = Resampler::resample_all(&samples, input_spec.sample_rate, device_rate, CHANNELS);
+    let duration_seconds = (samples.len() / CHANNELS) as f32 / device_rate as f32;
+
+    // Prepare the output wav file
+    let output_rate = args.output_rate.unwrap_or(device_rate);
+    let spec = audio_file::spec_for_device(supported_config.sample_format(), output_rate);
+    let writer = hound::WavWriter::create(&args.output, spec)?;
+
+    let processor = Processor {
+        input_channels: CHANNELS,
+        input_resampler: Resampler::new(device_rate, codec_rate, CHANNELS),
+        accumulator: FrameAccumulator::new(frame_size * CHANNELS),
+        pipeline,
+        output_resampler: Resampler::new(codec_rate, output_rate, CHANNELS),
+    };
+    // Encoding, the network and recording all happen on worker threads, fed
+    // through a ring buffer with room for a couple of seconds of audio
+    let sinks = Sinks {
+        wet: Some(writer),
+        ..Sinks::default()
     };
+    let (tap, workers) = Workers::spawn(processor, sinks, device_rate as usize * CHANNELS * 2);
 
-    // Read samples from input WAV and create stream based on format
-    let stream = match supported_config.sample_format() {
-        SampleFormat::F32 => {
-            let samples: Vec<f32> = input_file
-                .into_samples::<f32>()
-                .filter_map(Result::ok)
-                .collect();
-            let samples_clone = samples.clone();
-            let mut sample_idx = 0;
-
-            device.build_output_stream(
-                &supported_config.into(),
-                move |data: &mut [f32], _: &_| {
-                    for sample_out in data.iter_mut() {
-                        if sample_idx < samples_clone.len() {
-                            *sample_out = samples_clone[sample_idx];
-                            sample_idx += 1;
-                        } else {
-                            *sample_out = 0.0;
-                        }
-                    }
-                    write_input_data::<f32, f32>(
-                        data,
-                        &writer_clone,
-                        &mut encoder,
-                        &mut decoder,
-                        &network,
-                    );
-                },
-                err_fn,
-                None,
-            )?
-        }
-        SampleFormat::I16 => {
-            let samples: Vec<i16> = input_file
-                .into_samples::<i16>()
-                .filter_map(Result::ok)
-                .collect();
-            let samples_clone = samples.clone();
-            let mut sample_idx = 0;
-
-            device.build_output_stream(
-                &supported_config.into(),
-                move |data: &mut [i16], _: &_| {
-                    for sample_out in data.iter_mut() {
-                        if sample_idx < samples_clone.len() {
-                            *sample_out = samples_clone[sample_idx];
-                            sample_idx += 1;
-                        } else {
-                            *sample_out = 0;
-                        }
-                    }
-                    write_input_data::<i16, i16>(
-                        data,
-                        &writer_clone,
-                        &mut encoder,
-                        &mut decoder,
-                        &network,
-                    );
-                },
-                err_fn,
-                None,
-            )?
-        }
+    println!("Begin processing...");
+
+    // Create stream based on format
+    let sample_format = supported_config.sample_format();
+    let config = supported_config.into();
+    let stream = match sample_format {
+        SampleFormat::I8 => build_stream::<i8>(&device, &config, &samples, tap)?,
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, &samples, tap)?,
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, &samples, tap)?,
+        SampleFormat::I64 => build_stream::<i64>(&device, &config, &samples, tap)?,
+        SampleFormat::U8 => build_stream::<u8>(&device, &config, &samples, tap)?,
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, &samples, tap)?,
+        SampleFormat::U32 => build_stream::<u32>(&device, &config, &samples, tap)?,
+        SampleFormat::U64 => build_stream::<u64>(&device, &config, &samples, tap)?,
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, &samples, tap)?,
+        SampleFormat::F64 => build_stream::<f64>(&device, &config, &samples, tap)?,
         format => {
             return Err(anyhow::Error::msg(format!(
                 "Unsupported sample format '{format}'"
@@ -132,85 +175,55 @@ fn main() -> Result<(), anyhow::Error> {
     let duration = duration_seconds as u64;
     std::thread::sleep(std::time::Duration::from_secs(duration + 1));
 
-    // Clean up and finalize the recording
+    // Stopping the stream lets the workers drain what's left and finalize the recording
     drop(stream);
-    writer.lock().unwrap().take().unwrap().finalize()?;
-    println!("Processing {} complete!", PATH);
+    let (processor, overruns) = workers.join()?;
+    println!("{}", processor.pipeline.stats());
+    if overruns > 0 {
+        println!("{overruns} callbacks dropped because processing fell behind");
+    }
+    println!("Processing {} complete!", args.output.display());
     Ok(())
 }
 
-type WavWriterHandle = Arc<Mutex<Option<hound::WavWriter<BufWriter<File>>>>>;
-
-fn write_input_data<T, U>(
-    input: &[T],
-    writer: &WavWriterHandle,
-    encoder: &mut Encoder,
-    decoder: &mut Decoder,
-    network: &NetworkSimulator,
-) where
-    T: Sample + FromSample<f32>,
-    U: Sample + hound::Sample + FromSample<T>,
+// Plays `samples` on the device in its own sample format, handing whatever it
+// plays to the workers. The callback only copies samples in and out of memory
+// that's already allocated, so it never waits on anything.
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    samples: &[f32],
+    mut tap: CallbackTap,
+) -> Result<cpal::Stream, anyhow::Error>
+where
+    T: SizedSample + FromSample<f32> + Send + 'static,
     f32: FromSample<T>,
 {
-    if let Ok(mut guard) = writer.try_lock() {
-        if let Some(writer) = guard.as_mut() {
-            // Convert samples to f32 for Opus
-            let float_samples: Vec<f32> = input.iter().map(|&s| f32::from_sample(s)).collect();
-
-            // De-interleave stereo samples
-            let mut left_channel: Vec<f32> = Vec::with_capacity(float_samples.len() / 2);
-            let mut right_channel: Vec<f32> = Vec::with_capacity(float_samples.len() / 2);
-            for chunk in float_samples.chunks(2) {
-                left_channel.push(chunk[0]);
-                right_channel.push(chunk[1]);
-            }
+    let samples: Vec<T> = samples
+        .iter()
+        .map(|&sample| T::from_sample(sample))
+        .collect();
+    let mut sample_idx = 0;
 
-            let mut deinterleaved = Vec::with_capacity(float_samples.len());
-            deinterleaved.extend(&left_channel);
-            deinterleaved.extend(&right_channel);
-
-            // Encode with Opus
-            const FRAME_SIZE: usize = 960;
-            let mut frame = vec![0.0; FRAME_SIZE];
-            let copy_size = std::cmp::min(deinterleaved.len(), FRAME_SIZE);
-            frame[..copy_size].copy_from_slice(&deinterleaved[..copy_size]);
-            let mut encoded = vec![0u8; 1275]; // Max opus packet size
-            let encoded_len = encoder
-                .encode_float(&frame, &mut encoded)
-                .expect("Failed to encode");
-
-            // Simulate network conditions
-            if let Some(received_packet) = network.simulate_network(encoded) {
-                // Decode with Opus
-                let mut decoded = vec![0f32; 960]; // Frame size
-                let decoded_len = decoder
-                    .decode_float(&received_packet, &mut decoded, false)
-                    .expect("Failed to decode");
-
-                // Write decoded samples to both channels
-                for sample in decoded[..decoded_len].iter() {
-                    let sample: U = U::from_sample(Sample::from_sample(*sample));
-                    writer.write_sample(sample).ok(); // Left channel
-                    writer.write_sample(sample).ok(); // Right channel
+    let err_fn = move |err| {
+        eprintln!("an error occurred on stream: {}", err);
+    };
+
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &_| {
+            for sample_out in data.iter_mut() {
+                if sample_idx < samples.len() {
+                    *sample_out = samples[sample_idx];
+                    sample_idx += 1;
+                } else {
+                    *sample_out = T::EQUILIBRIUM;
                 }
             }
-        }
-    }
-}
-
-fn wav_file_spec_from_config(config: &cpal::SupportedStreamConfig) -> hound::WavSpec {
-    hound::WavSpec {
-        channels: 2,
-        sample_rate: config.sample_rate().0 as _,
-        bits_per_sample: (config.sample_format().sample_size() * 8) as _,
-        sample_format: sample_format_converter(config.sample_format()),
-    }
-}
-
-fn sample_format_converter(format: cpal::SampleFormat) -> hound::SampleFormat {
-    if format.is_float() {
-        hound::SampleFormat::Float
-    } else {
-        hound::SampleFormat::Int
-    }
+            tap.push(data);
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
 }
@@ -0,0 +1,365 @@
+use crate::pipeline::CHANNELS;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+// How the decoded signal is blended back with the input, and what happens to it
+// while packets are being lost
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MixConfig {
+    // Share of the processed signal in the output, from 0.0 (all dry) to 1.0 (all wet)
+    pub wet: f32,
+    // Applied in order to the processed signal around lost frames only
+    pub loss_effects: Vec<LossEffect>,
+    // How fast the effects come in at a loss, and die away once packets get through again
+    pub attack_ms: f64,
+    pub release_ms: f64,
+}
+
+impl Default for MixConfig {
+    fn default() -> Self {
+        Self {
+            wet: 1.0,
+            loss_effects: Vec::new(),
+            attack_ms: 5.0,
+            release_ms: 150.0,
+        }
+    }
+}
+
+impl MixConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // Whether the mixer would pass the decoded signal through untouched
+    pub fn is_bypassed(&self) -> bool {
+        self.wet >= 1.0 && self.loss_effects.is_empty()
+    }
+
+    pub fn build_mixer(&self, sample_rate: u32) -> Mixer {
+        let mut mixer =
+            Mixer::new(self.wet, sample_rate).with_envelope(self.attack_ms, self.release_ms);
+        for effect in &self.loss_effects {
+            mixer = mixer.with_effect(effect.build(sample_rate));
+        }
+        mixer
+    }
+}
+
+// Something done to the decoded signal while packets are lost. It runs on every
+// frame so filters keep their state, and the mixer fades its output in and out.
+// Any type implementing this can be handed to `Mixer::with_effect`.
+pub trait Effect: Send {
+    // Processes interleaved stereo samples in place
+    fn process(&mut self, samples: &mut [f32]);
+}
+
+// The built-in loss effects
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "effect", rename_all = "kebab-case")]
+pub enum LossEffect {
+    // Muffles the sound above `cutoff_hz`
+    #[serde(rename = "lowpass")]
+    LowPass {
+        cutoff_hz: f32,
+    },
+    // Cuts the resolution to `bits` and holds each sample for `downsample` samples
+    Bitcrush {
+        bits: u32,
+        downsample: u32,
+    },
+    // Turns the level down by `gain_db`, which is usually negative
+    Duck {
+        gain_db: f32,
+    },
+}
+
+impl LossEffect {
+    pub fn build(&self, sample_rate: u32) -> Box<dyn Effect> {
+        match *self {
+            LossEffect::LowPass { cutoff_hz } => {
+                // One pole, with the coefficient for the cutoff at this rate
+                let cutoff_hz = cutoff_hz.clamp(1.0, sample_rate as f32 / 2.0);
+                let coefficient =
+                    1.0 - (-2.0 * std::f32::consts::PI * cutoff_hz / sample_rate as f32).exp();
+                Box::new(LowPass {
+                    coefficient,
+                    state: [0.0; CHANNELS],
+                })
+            }
+            LossEffect::Bitcrush { bits, downsample } => Box::new(Bitcrush {
+                steps: 2f32.powi(bits.clamp(1, 24) as i32 - 1),
+                downsample: downsample.max(1),
+                held: [0.0; CHANNELS],
+                position: 0,
+            }),
+            LossEffect::Duck { gain_db } => Box::new(Duck {
+                gain: 10f32.powf(gain_db / 20.0),
+            }),
+        }
+    }
+}
+
+// "lowpass:<cutoff Hz>", "bitcrush:<bits>[:<downsample>]" or "duck:<gain dB>"
+impl FromStr for LossEffect {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected lowpass:<cutoff Hz>, bitcrush:<bits>[:<downsample>] or \
+                 duck:<gain dB>, got '{text}'"
+            )
+        };
+        let fields: Vec<&str> = text.split(':').collect();
+        match fields.as_slice() {
+            ["lowpass", cutoff_hz] => Ok(LossEffect::LowPass {
+                cutoff_hz: cutoff_hz.parse().map_err(|_| invalid())?,
+            }),
+            ["bitcrush", bits] => Ok(LossEffect::Bitcrush {
+                bits: bits.parse().map_err(|_| invalid())?,
+                downsample: 1,
+            }),
+            ["bitcrush", bits, downsample] => Ok(LossEffect::Bitcrush {
+                bits: bits.parse().map_err(|_| invalid())?,
+                downsample: downsample.parse().map_err(|_| invalid())?,
+            }),
+            ["duck", gain_db] => Ok(LossEffect::Duck {
+                gain_db: gain_db.parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+struct LowPass {
+    coefficient: f32,
+    state: [f32; CHANNELS],
+}
+
+impl Effect for LowPass {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(CHANNELS) {
+            for (sample, state) in frame.iter_mut().zip(self.state.iter_mut()) {
+                *state += self.coefficient * (*sample - *state);
+                *sample = *state;
+            }
+        }
+    }
+}
+
+struct Bitcrush {
+    // Quantization steps on each side of zero
+    steps: f32,
+    downsample: u32,
+    held: [f32; CHANNELS],
+    position: u32,
+}
+
+impl Effect for Bitcrush {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(CHANNELS) {
+            if self.position == 0 {
+                for (held, &sample) in self.held.iter_mut().zip(frame.iter()) {
+                    *held = (sample * self.steps).round() / self.steps;
+                }
+            }
+            frame.copy_from_slice(&self.held);
+            self.position = (self.position + 1) % self.downsample;
+        }
+    }
+}
+
+struct Duck {
+    gain: f32,
+}
+
+impl Effect for Duck {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            *sample *= self.gain;
+        }
+    }
+}
+
+// Blends each decoded frame with the input it came from, and brings the loss
+// effects in around frames that were lost. The dry input is held back until the
+// frame made from it plays, so both line up however long the network and jitter
+// buffer take.
+pub struct Mixer {
+    wet: f32,
+    effects: Vec<Box<dyn Effect>>,
+    // How far the effects are faded in, and by how much that moves per sample
+    envelope: f32,
+    attack_step: f32,
+    release_step: f32,
+    sample_rate: u32,
+    // Input waiting for its frame to play. `dry_start` is the position of the first
+    // sample in it, counted in frames of audio from the start of the stream.
+    dry: VecDeque<f32>,
+    dry_start: u64,
+    // Scratch space for the effected signal
+    effected: Vec<f32>,
+}
+
+impl Mixer {
+    pub fn new(wet: f32, sample_rate: u32) -> Self {
+        Self {
+            wet: wet.clamp(0.0, 1.0),
+            effects: Vec::new(),
+            envelope: 0.0,
+            attack_step: 1.0,
+            release_step: 1.0,
+            sample_rate,
+            dry: VecDeque::new(),
+            dry_start: 0,
+            effected: Vec::new(),
+        }
+    }
+
+    pub fn with_effect(mut self, effect: Box<dyn Effect>) -> Self {
+        self.effects.push(effect);
+        self
+    }
+
+    pub fn with_envelope(mut self, attack_ms: f64, release_ms: f64) -> Self {
+        self.attack_step = self.ramp_step(attack_ms);
+        self.release_step = self.ramp_step(release_ms);
+        self
+    }
+
+    // Holds the dry signal back by `frames` more, to match a delay in the codec itself
+    pub fn with_dry_delay(mut self, frames: usize) -> Self {
+        self.dry.extend(std::iter::repeat_n(0.0, frames * CHANNELS));
+        self
+    }
+
+    pub fn set_wet(&mut self, wet: f32) {
+        self.wet = wet.clamp(0.0, 1.0);
+    }
+
+    // Keeps an interleaved frame of input until the frame decoded from it plays
+    pub fn push_dry(&mut self, frame: &[f32]) {
+        self.dry.extend(frame);
+    }
+
+    // Mixes `wet`, decoded from the frame that started at `position` frames of
+    // audio into the input, with that input. `lost` says whether its packet got
+    // through intact.
+    pub fn process(&mut self, position: u64, wet: &mut [f32], lost: bool) {
+        // Anything before this frame's input has been played already
+        let skip =
+            (position.saturating_sub(self.dry_start) as usize * CHANNELS).min(self.dry.len());
+        self.dry.drain(..skip);
+        self.dry_start += (skip / CHANNELS) as u64;
+
+        if !self.effects.is_empty() {
+            self.apply_effects(wet, lost);
+        }
+
+        let dry_gain = 1.0 - self.wet;
+        for (index, sample) in wet.iter_mut().enumerate() {
+            let dry = self.dry.get(index).copied().unwrap_or(0.0);
+            *sample = *sample * self.wet + dry * dry_gain;
+        }
+    }
+
+    fn apply_effects(&mut self, wet: &mut [f32], lost: bool) {
+        self.effected.clear();
+        self.effected.extend_from_slice(wet);
+        for effect in &mut self.effects {
+            effect.process(&mut self.effected);
+        }
+
+        for (frame, effected) in wet
+            .chunks_exact_mut(CHANNELS)
+            .zip(self.effected.chunks_exact(CHANNELS))
+        {
+            self.envelope = if lost {
+                (self.envelope + self.attack_step).min(1.0)
+            } else {
+                (self.envelope - self.release_step).max(0.0)
+            };
+            for (sample, &effected) in frame.iter_mut().zip(effected) {
+                *sample += (effected - *sample) * self.envelope;
+            }
+        }
+    }
+
+    // Change in the envelope per sample for a ramp lasting `ms`
+    fn ramp_step(&self, ms: f64) -> f32 {
+        let samples = ms.max(0.0) / 1000.0 * self.sample_rate as f64;
+        (1.0 / samples.max(1.0)) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = 4;
+
+    // Interleaved stereo input with every sample of frame `index` set to `index + 1`
+    fn input(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|index| [index as f32 + 1.0; CHANNELS])
+            .collect()
+    }
+
+    // `samples` held back by `frames`, as the codec does to the wet path
+    fn delayed(samples: &[f32], frames: usize) -> Vec<f32> {
+        let mut delayed = vec![0.0; frames * CHANNELS];
+        delayed.extend_from_slice(&samples[..samples.len() - frames * CHANNELS]);
+        delayed
+    }
+
+    #[test]
+    fn dry_delay_lines_up_with_the_wet_path() {
+        const LOOKAHEAD: usize = 3;
+        const LATENCY_CHUNKS: usize = 2;
+        let input = input(CHUNK * 8);
+        let wet_path = delayed(&input, LOOKAHEAD);
+        let mut mixer = Mixer::new(0.5, 48_000).with_dry_delay(LOOKAHEAD);
+
+        // Input keeps coming while earlier chunks are still on their way through
+        // the network, so the mixer has to find the right part of it
+        let mut output = Vec::new();
+        for (index, chunk) in input.chunks(CHUNK * CHANNELS).enumerate() {
+            mixer.push_dry(chunk);
+            if let Some(played) = index.checked_sub(LATENCY_CHUNKS) {
+                let start = played * CHUNK * CHANNELS;
+                let mut wet = wet_path[start..start + CHUNK * CHANNELS].to_vec();
+                mixer.process((played * CHUNK) as u64, &mut wet, false);
+                output.extend(wet);
+            }
+        }
+        // Half of each, adding up to the delayed input when they line up
+        assert_eq!(output, wet_path[..output.len()]);
+    }
+
+    #[test]
+    fn all_dry_plays_the_delayed_input() {
+        let input = input(CHUNK * 2);
+        let mut mixer = Mixer::new(0.0, 48_000).with_dry_delay(1);
+        mixer.push_dry(&input);
+        let mut output = vec![9.0; input.len()];
+        mixer.process(0, &mut output, false);
+        assert_eq!(output, delayed(&input, 1));
+    }
+
+    #[test]
+    fn effects_only_apply_to_lost_frames() {
+        let mut mixer = Mixer::new(1.0, 48_000)
+            .with_envelope(0.0, 0.0)
+            .with_effect(LossEffect::Duck { gain_db: -20.0 }.build(48_000));
+        let mut received = vec![1.0; CHUNK * CHANNELS];
+        mixer.process(0, &mut received, false);
+        assert_eq!(received, vec![1.0; CHUNK * CHANNELS]);
+
+        let mut lost = vec![1.0; CHUNK * CHANNELS];
+        mixer.process(CHUNK as u64, &mut lost, true);
+        assert!(lost.iter().all(|&sample| (sample - 0.1).abs() < 1e-6));
+    }
+}
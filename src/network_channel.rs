@@ -0,0 +1,128 @@
+use crate::jitter_buffer::{JitterBuffer, JitterBufferStats, Playout};
+use crate::network_simulator::{NetworkSimulator, Packet};
+use crate::step_sequencer::SequencerStats;
+use std::fmt;
+
+// The packet side of the chain: the simulated network and the receiver's jitter
+// buffer behind it. Packets go in as they're sent and frames come out as they're
+// due, both on the same virtual clock.
+pub struct NetworkChannel {
+    network: NetworkSimulator,
+    jitter_buffer: JitterBuffer,
+    // Packets just taken off the network, kept to save allocating every time
+    arrived: Vec<Packet>,
+}
+
+impl NetworkChannel {
+    pub fn new(network: NetworkSimulator, jitter_buffer: JitterBuffer) -> Self {
+        Self {
+            network,
+            jitter_buffer,
+            arrived: Vec::new(),
+        }
+    }
+
+    pub fn send(&mut self, sequence: u64, payload: Vec<u8>, now_us: u64) {
+        self.network.send(sequence, payload, now_us);
+    }
+
+    // Hands everything that has arrived by `now_us` to the jitter buffer and returns
+    // the next frame if it's due
+    pub fn pop(&mut self, now_us: u64) -> Option<Playout> {
+        self.network.receive(now_us, &mut self.arrived);
+        for packet in self.arrived.drain(..) {
+            if let Some(rejected) = self.jitter_buffer.push(packet) {
+                self.network.recycle(rejected.payload);
+            }
+        }
+        self.jitter_buffer.pop(now_us)
+    }
+
+    // An empty buffer for the next payload to send
+    pub fn payload_buffer(&mut self) -> Vec<u8> {
+        self.network.payload_buffer()
+    }
+
+    // Takes back a payload once it's been decoded, to send another one in
+    pub fn recycle(&mut self, payload: Vec<u8>) {
+        self.network.recycle(payload);
+    }
+
+    // The packet for the frame after the one just popped, if it's already here
+    pub fn peek_next(&self) -> Option<&Packet> {
+        self.jitter_buffer.peek_next()
+    }
+
+    // Sequence number of the next frame to be played
+    pub fn next_sequence(&self) -> u64 {
+        self.jitter_buffer.next_sequence()
+    }
+
+    pub fn network(&self) -> &NetworkSimulator {
+        &self.network
+    }
+
+    pub fn network_mut(&mut self) -> &mut NetworkSimulator {
+        &mut self.network
+    }
+
+    pub fn jitter_buffer(&self) -> &JitterBuffer {
+        &self.jitter_buffer
+    }
+
+    // What happened to the packets on their way through so far
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            jitter_buffer: self.jitter_buffer.stats(),
+            depth_us: self.jitter_buffer.depth_us(),
+            bottleneck_drops: self
+                .network
+                .bottleneck()
+                .map(|bottleneck| bottleneck.dropped()),
+            sequencer: self.network.sequencer().map(|sequencer| sequencer.stats()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelStats {
+    pub jitter_buffer: JitterBufferStats,
+    // Jitter buffer depth at the time
+    pub depth_us: u64,
+    // Packets dropped by the bottleneck queue, if there is one
+    pub bottleneck_drops: Option<u64>,
+    pub sequencer: Option<SequencerStats>,
+}
+
+// One line per thing worth mentioning, without a trailing newline
+impl fmt::Display for ChannelStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = self.jitter_buffer;
+        write!(
+            f,
+            "Played {} frames, {} missing ({} arrived too late), final buffer depth {} ms",
+            stats.played,
+            stats.missing,
+            stats.late,
+            self.depth_us / 1000
+        )?;
+        if stats.duplicates > 0 || stats.reordered > 0 {
+            write!(
+                f,
+                "\n{} packets arrived out of order, {} duplicates discarded",
+                stats.reordered, stats.duplicates
+            )?;
+        }
+        if let Some(dropped) = self.bottleneck_drops {
+            write!(f, "\n{dropped} packets dropped by the bottleneck queue")?;
+        }
+        if let Some(stats) = self.sequencer {
+            write!(
+                f,
+                "\nStep sequencer dropped {} steps, froze {} and repeated {}",
+                stats.dropped_steps, stats.frozen_steps, stats.repeated_steps
+            )?;
+        }
+        Ok(())
+    }
+}
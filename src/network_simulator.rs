@@ -1,34 +1,369 @@
-use rand::random;
-use std::thread;
-use std::time::Duration;
+use crate::automation::NetworkAutomation;
+use crate::bottleneck::Bottleneck;
+use crate::corruption::Corruption;
+use crate::loss_model::LossModel;
+use crate::step_sequencer::StepSequencer;
+use crate::trace::NetworkTrace;
+use rand::rngs::StdRng;
+use rand::{random, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+// Packets there's room for on the wire before the in-flight list has to grow
+const IN_FLIGHT_CAPACITY: usize = 256;
+// Most payload buffers kept for reuse
+const SPARE_PAYLOADS: usize = 256;
+
+// A packet travelling through the simulated network, stamped with virtual times
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub sequence: u64,
+    pub sent_at_us: u64,
+    pub arrival_us: u64,
+    pub payload: Vec<u8>,
+}
+
+// Packets delivered more than once or out of order. All off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Delivery {
+    pub duplicate_probability: f64,
+    pub reorder_probability: f64,
+    // How many later packets overtake a reordered one, at least one
+    pub reorder_depth: u32,
+}
+
+impl Delivery {
+    pub fn is_disabled(&self) -> bool {
+        self.duplicate_probability <= 0.0 && self.reorder_probability <= 0.0
+    }
+}
 
 // Struct to simulate network conditions
-// todo: update this as needed
+// Time is virtual: callers say when a packet is sent and ask what has arrived by a
+// given time, so nothing ever sleeps and the same run can be replayed offline
 pub struct NetworkSimulator {
-    pub packet_loss_probability: f32,
+    pub loss_model: LossModel,
     pub latency_us: u64,
     pub jitter_us: u64,
+    // Moves the conditions above over time, applied as each packet is sent
+    automation: NetworkAutomation,
+    // Congested link that packets queue for before the latency and jitter
+    bottleneck: Option<Bottleneck>,
+    delivery: Delivery,
+    // Time between the last two packets sent, to work out how far to hold back a
+    // packet for it to be overtaken by the next few
+    last_send_us: Option<u64>,
+    send_interval_us: u64,
+    // Pattern of drops, freezes and repeats on a musical grid, ahead of the loss model
+    sequencer: Option<StepSequencer>,
+    // Damage done to the packets that get through
+    corruption: Corruption,
+    corrupted_packets: u64,
+    // Recorded per-packet loss and delay, overriding everything above when set
+    trace: Option<NetworkTrace>,
+    // Every random decision comes from this generator, so a given seed always
+    // produces the same pattern of losses and delays
+    rng: StdRng,
+    seed: u64,
+    in_flight: Vec<Packet>,
+    // Payload buffers of packets that are done with, handed out again for new
+    // packets so a steady stream doesn't allocate
+    spare_payloads: Vec<Vec<u8>>,
 }
 
 impl NetworkSimulator {
     pub fn new(packet_loss_probability: f32, latency_us: u64, jitter_us: u64) -> Self {
+        let seed = random();
         Self {
-            packet_loss_probability,
+            loss_model: LossModel::Bernoulli {
+                loss_probability: packet_loss_probability,
+            },
             latency_us,
             jitter_us,
+            automation: NetworkAutomation::default(),
+            bottleneck: None,
+            delivery: Delivery::default(),
+            last_send_us: None,
+            send_interval_us: 0,
+            sequencer: None,
+            corruption: Corruption::default(),
+            corrupted_packets: 0,
+            trace: None,
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            in_flight: Vec::with_capacity(IN_FLIGHT_CAPACITY),
+            spare_payloads: Vec::with_capacity(SPARE_PAYLOADS),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.seed = seed;
+        self
+    }
+
+    // The seed in use, so a run with a randomly picked seed can be reproduced later
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Swaps the default independent loss for another model, e.g. bursty Gilbert-Elliott loss
+    pub fn with_loss_model(mut self, loss_model: LossModel) -> Self {
+        self.loss_model = loss_model;
+        self
+    }
+
+    pub fn with_automation(mut self, automation: NetworkAutomation) -> Self {
+        self.automation = automation;
+        self
+    }
+
+    pub fn with_bottleneck(mut self, bottleneck: Bottleneck) -> Self {
+        self.bottleneck = Some(bottleneck);
+        self
+    }
+
+    pub fn bottleneck(&self) -> Option<&Bottleneck> {
+        self.bottleneck.as_ref()
+    }
+
+    pub fn with_delivery(mut self, delivery: Delivery) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    pub fn with_sequencer(mut self, sequencer: StepSequencer) -> Self {
+        self.sequencer = Some(sequencer);
+        self
+    }
+
+    pub fn sequencer(&self) -> Option<&StepSequencer> {
+        self.sequencer.as_ref()
+    }
+
+    pub fn with_corruption(mut self, corruption: Corruption) -> Self {
+        self.corruption = corruption;
+        self
+    }
+
+    pub fn corrupted_packets(&self) -> u64 {
+        self.corrupted_packets
+    }
+
+    pub fn with_trace(mut self, trace: NetworkTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn trace(&self) -> Option<&NetworkTrace> {
+        self.trace.as_ref()
+    }
+
+    // An empty buffer to send a payload in, reusing one from an earlier packet if
+    // there is one
+    pub fn payload_buffer(&mut self) -> Vec<u8> {
+        self.spare_payloads.pop().unwrap_or_default()
+    }
+
+    // Takes back the payload of a packet that's been played or thrown away
+    pub fn recycle(&mut self, mut payload: Vec<u8>) {
+        if self.spare_payloads.len() < SPARE_PAYLOADS {
+            payload.clear();
+            self.spare_payloads.push(payload);
         }
     }
 
-    pub fn simulate_network(&self, packet: Vec<u8>) -> Option<Vec<u8>> {
+    // Puts a packet on the wire at `now_us`. Lost packets simply never arrive.
+    pub fn send(&mut self, sequence: u64, payload: Vec<u8>, now_us: u64) {
+        if let Some(trace) = &self.trace {
+            if let Some(delay_us) = trace.delay_us(sequence) {
+                self.in_flight.push(Packet {
+                    sequence,
+                    sent_at_us: now_us,
+                    arrival_us: now_us + delay_us,
+                    payload,
+                });
+            } else {
+                self.recycle(payload);
+            }
+            return;
+        }
+
+        if let Some(last_send_us) = self.last_send_us {
+            self.send_interval_us = now_us.saturating_sub(last_send_us);
+        }
+        self.last_send_us = Some(now_us);
+        self.apply_automation(now_us);
+
+        let payload = match &mut self.sequencer {
+            Some(sequencer) => match sequencer.apply(now_us, payload, &mut self.rng) {
+                Some(payload) => payload,
+                None => return,
+            },
+            None => payload,
+        };
+
         // Simulate packet loss
-        if random::<f32>() < self.packet_loss_probability {
-            return None;
+        if self.loss_model.should_drop(&mut self.rng) {
+            self.recycle(payload);
+            return;
+        }
+
+        let packet = Packet {
+            sequence,
+            sent_at_us: now_us,
+            arrival_us: now_us,
+            payload,
+        };
+        // Let the link catch up first so the queue is as full as it really is
+        self.drain_bottleneck(now_us);
+        match &mut self.bottleneck {
+            Some(bottleneck) => bottleneck.enqueue(packet, now_us, &mut self.rng),
+            None => self.transmit(packet),
+        }
+    }
+
+    // Sends a packet that's ready to leave at `arrival_us` across the rest of the network
+    fn transmit(&mut self, packet: Packet) {
+        let duplicate_probability = self.delivery.duplicate_probability;
+        if duplicate_probability > 0.0 && self.rng.gen::<f64>() < duplicate_probability {
+            // Each copy takes its own path, so either may arrive first
+            self.deliver(packet.clone());
         }
+        self.deliver(packet);
+    }
 
+    fn deliver(&mut self, mut packet: Packet) {
         // Simulate latency and jitter using microseconds
-        let jitter = random::<u64>() % self.jitter_us;
-        thread::sleep(Duration::from_micros(self.latency_us + jitter));
+        let jitter = if self.jitter_us > 0 {
+            self.rng.gen_range(0..self.jitter_us)
+        } else {
+            0
+        };
+        packet.arrival_us += self.latency_us + jitter;
+
+        let reorder_probability = self.delivery.reorder_probability;
+        if reorder_probability > 0.0 && self.rng.gen::<f64>() < reorder_probability {
+            // Land halfway between the packets `reorder_depth` and one more behind it
+            let depth = self.delivery.reorder_depth.max(1) as u64;
+            packet.arrival_us += depth * self.send_interval_us + self.send_interval_us / 2;
+        }
+        if self.corruption.corrupt(&mut packet.payload, &mut self.rng) {
+            self.corrupted_packets += 1;
+        }
+        self.in_flight.push(packet);
+    }
+
+    fn drain_bottleneck(&mut self, now_us: u64) {
+        let Some(bottleneck) = &mut self.bottleneck else {
+            return;
+        };
+        for packet in bottleneck.dequeue_until(now_us) {
+            self.transmit(packet);
+        }
+    }
+
+    fn apply_automation(&mut self, now_us: u64) {
+        let time_s = now_us as f64 / 1_000_000.0;
+        if let Some(loss) = &mut self.automation.loss {
+            let loss_probability = loss.value_at(time_s, &mut self.rng).clamp(0.0, 1.0);
+            self.loss_model
+                .set_average_loss_probability(loss_probability as f32);
+        }
+        if let Some(latency_us) = &mut self.automation.latency_us {
+            self.latency_us = latency_us.value_at(time_s, &mut self.rng).max(0.0) as u64;
+        }
+        if let Some(jitter_us) = &mut self.automation.jitter_us {
+            self.jitter_us = jitter_us.value_at(time_s, &mut self.rng).max(0.0) as u64;
+        }
+    }
+
+    // Moves every packet that has arrived by `now_us` to `arrived`, in arrival order.
+    // With enough jitter that's not necessarily sequence order.
+    pub fn receive(&mut self, now_us: u64, arrived: &mut Vec<Packet>) {
+        self.drain_bottleneck(now_us);
+
+        let first = arrived.len();
+        let mut index = 0;
+        while index < self.in_flight.len() {
+            if self.in_flight[index].arrival_us <= now_us {
+                arrived.push(self.in_flight.swap_remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        // Unstable, as a stable sort would allocate
+        arrived[first..].sort_unstable_by_key(|packet| (packet.arrival_us, packet.sequence));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL_US: u64 = 20_000;
+
+    // Sends `count` packets 20 ms apart on a lossless link without jitter and
+    // returns everything that arrives, in arrival order
+    fn run(delivery: Delivery, count: u64) -> Vec<Packet> {
+        let mut network = NetworkSimulator::new(0.0, 10_000, 0)
+            .with_seed(7)
+            .with_delivery(delivery);
+        for sequence in 0..count {
+            network.send(sequence, vec![0; 10], sequence * INTERVAL_US);
+        }
+        let mut arrived = Vec::new();
+        network.receive(u64::MAX, &mut arrived);
+        arrived
+    }
+
+    #[test]
+    fn duplicates_at_the_given_rate() {
+        let arrived = run(
+            Delivery {
+                duplicate_probability: 0.1,
+                ..Delivery::default()
+            },
+            10_000,
+        );
+        let duplicates = arrived.len() - 10_000;
+        assert!((900..1100).contains(&duplicates), "{duplicates} duplicates");
+    }
+
+    #[test]
+    fn reorders_at_the_given_rate_and_depth() {
+        let arrived = run(
+            Delivery {
+                reorder_probability: 0.1,
+                reorder_depth: 2,
+                ..Delivery::default()
+            },
+            10_000,
+        );
+        assert_eq!(arrived.len(), 10_000);
+
+        let late: Vec<&Packet> = arrived
+            .iter()
+            .filter(|packet| packet.arrival_us != packet.sent_at_us + 10_000)
+            .collect();
+        assert!(
+            (900..1100).contains(&late.len()),
+            "{} reordered",
+            late.len()
+        );
+        // Held back to land between the second and third packets after it
+        for packet in late {
+            assert_eq!(
+                packet.arrival_us,
+                packet.sent_at_us + 10_000 + 2 * INTERVAL_US + INTERVAL_US / 2
+            );
+        }
+    }
 
-        Some(packet)
+    #[test]
+    fn disabled_keeps_order() {
+        let arrived = run(Delivery::default(), 100);
+        let sequences: Vec<u64> = arrived.iter().map(|packet| packet.sequence).collect();
+        assert_eq!(sequences, (0..100).collect::<Vec<u64>>());
     }
 }
@@ -0,0 +1,40 @@
+use rust_opus_test::audio_file;
+use rust_opus_test::graph::{BufferSource, CodecStage, Graph, WavSink};
+use rust_opus_test::pipeline::{Pipeline, CHANNELS};
+use rust_opus_test::resampler::Resampler;
+use std::path::Path;
+
+// Renders a WAV file through the glitch pipeline without touching any audio
+// device. Frames are processed back to back, so the output only depends on the
+// input and the pipeline settings, not on wall-clock timing. The input is
+// converted to stereo at the codec rate, and the result to `output_rate`, or the
+// input's own rate if not given.
+pub fn render(
+    input_path: &Path,
+    output_path: &Path,
+    output_rate: Option<u32>,
+    pipeline: Pipeline,
+) -> Result<(), anyhow::Error> {
+    let (source, input_spec) = BufferSource::from_wav(input_path)?;
+    println!("Input WAV spec: {:?}", input_spec);
+
+    let codec_rate = pipeline.sample_rate();
+    let output_rate = output_rate.unwrap_or(input_spec.sample_rate);
+    let mut graph = Graph::new(source)
+        .with_stage(Resampler::new(input_spec.sample_rate, codec_rate, CHANNELS))
+        .with_stage(CodecStage::new(pipeline))
+        .with_stage(Resampler::new(codec_rate, output_rate, CHANNELS))
+        .with_sink(WavSink::create(
+            output_path,
+            audio_file::float_spec(output_rate),
+        )?);
+
+    println!("Begin rendering...");
+    graph.run()?;
+
+    for stats in graph.stats() {
+        println!("{stats}");
+    }
+    println!("Rendering {} complete!", output_path.display());
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use crate::codec::Signal;
+use audiopus_sys as ffi;
+use opus::{Application, Bandwidth, Bitrate, Channels};
+use std::ffi::CStr;
+use std::os::raw::c_int;
+
+// The opus crate only exposes bitrate, VBR and FEC controls on its encoder, so
+// this wraps libopus directly to also reach complexity, signal type, bandwidth
+// and DTX. Decoding still goes through opus::Decoder.
+pub struct OpusEncoder {
+    ptr: *mut ffi::OpusEncoder,
+    channels: Channels,
+}
+
+// libopus encoder state isn't tied to the thread that created it, it just can't be
+// used from two threads at once, which &mut already rules out
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+    pub fn new(
+        sample_rate: u32,
+        channels: Channels,
+        application: Application,
+    ) -> Result<Self, anyhow::Error> {
+        let mut error = 0;
+        let ptr = unsafe {
+            ffi::opus_encoder_create(
+                sample_rate as i32,
+                channels as c_int,
+                application as c_int,
+                &mut error,
+            )
+        };
+        if error != ffi::OPUS_OK || ptr.is_null() {
+            return Err(opus_error("opus_encoder_create", error));
+        }
+        Ok(Self { ptr, channels })
+    }
+
+    // Encodes one frame of interleaved samples, returning the packet length in bytes
+    pub fn encode_float(
+        &mut self,
+        input: &[f32],
+        output: &mut [u8],
+    ) -> Result<usize, anyhow::Error> {
+        let frame_size = input.len() / self.channels as usize;
+        let len = unsafe {
+            ffi::opus_encode_float(
+                self.ptr,
+                input.as_ptr(),
+                frame_size as c_int,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(opus_error("opus_encode_float", len));
+        }
+        Ok(len as usize)
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<(), anyhow::Error> {
+        let value = match bitrate {
+            Bitrate::Bits(bits) => bits,
+            Bitrate::Max => -1,
+            Bitrate::Auto => ffi::OPUS_AUTO,
+        };
+        self.ctl(ffi::OPUS_SET_BITRATE_REQUEST, value)
+    }
+
+    pub fn set_vbr(&mut self, vbr: bool) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_VBR_REQUEST, vbr as i32)
+    }
+
+    pub fn set_vbr_constraint(&mut self, constrained: bool) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_VBR_CONSTRAINT_REQUEST, constrained as i32)
+    }
+
+    // 0 is the cheapest and lowest quality, 10 the most expensive
+    pub fn set_complexity(&mut self, complexity: i32) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST, complexity)
+    }
+
+    pub fn set_signal(&mut self, signal: Signal) -> Result<(), anyhow::Error> {
+        let value = match signal {
+            Signal::Auto => ffi::OPUS_AUTO,
+            Signal::Voice => ffi::OPUS_SIGNAL_VOICE,
+            Signal::Music => ffi::OPUS_SIGNAL_MUSIC,
+        };
+        self.ctl(ffi::OPUS_SET_SIGNAL_REQUEST, value)
+    }
+
+    pub fn set_max_bandwidth(&mut self, bandwidth: Bandwidth) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_MAX_BANDWIDTH_REQUEST, bandwidth as i32)
+    }
+
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_DTX_REQUEST, dtx as i32)
+    }
+
+    pub fn set_inband_fec(&mut self, fec: bool) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_INBAND_FEC_REQUEST, fec as i32)
+    }
+
+    pub fn set_packet_loss_perc(&mut self, percent: i32) -> Result<(), anyhow::Error> {
+        self.ctl(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, percent)
+    }
+
+    // Samples the encoder holds back to look ahead, which delays everything that
+    // comes out of the decoder by as much
+    pub fn lookahead(&mut self) -> Result<usize, anyhow::Error> {
+        let mut lookahead: c_int = 0;
+        let result = unsafe {
+            ffi::opus_encoder_ctl(
+                self.ptr,
+                ffi::OPUS_GET_LOOKAHEAD_REQUEST,
+                &mut lookahead as *mut c_int,
+            )
+        };
+        if result != ffi::OPUS_OK {
+            return Err(opus_error("opus_encoder_ctl", result));
+        }
+        Ok(lookahead as usize)
+    }
+
+    fn ctl(&mut self, request: i32, value: i32) -> Result<(), anyhow::Error> {
+        let result = unsafe { ffi::opus_encoder_ctl(self.ptr, request, value as c_int) };
+        if result != ffi::OPUS_OK {
+            return Err(opus_error("opus_encoder_ctl", result));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_encoder_destroy(self.ptr) }
+    }
+}
+
+fn opus_error(function: &str, code: c_int) -> anyhow::Error {
+    let description = unsafe { CStr::from_ptr(ffi::opus_strerror(code)) };
+    anyhow::Error::msg(format!(
+        "{function} failed: {}",
+        description.to_string_lossy()
+    ))
+}
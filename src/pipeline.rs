@@ -0,0 +1,282 @@
+use crate::codec::FrameDuration;
+use crate::jitter_buffer::Playout;
+use crate::loss_strategy::{LossHandler, LossStrategy};
+use crate::mixer::Mixer;
+use crate::network_channel::{ChannelStats, NetworkChannel};
+use crate::opus_encoder::OpusEncoder;
+use opus::Decoder;
+use std::fmt;
+
+pub const CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineStats {
+    pub channel: ChannelStats,
+    // Missing frames filled in by the loss handler
+    pub concealed_frames: u64,
+    // Missing frames rebuilt from FEC data
+    pub recovered_frames: u64,
+    pub corrupted_packets: u64,
+    // Packets that arrived too damaged to decode
+    pub undecodable_frames: u64,
+}
+
+// The encoder -> network -> jitter buffer -> decoder chain, driven by a virtual
+// clock that advances one frame for every frame pushed in
+pub struct Pipeline {
+    encoder: OpusEncoder,
+    decoder: Decoder,
+    channel: NetworkChannel,
+    frame_duration: FrameDuration,
+    sample_rate: u32,
+    next_sequence: u64,
+    // Whether lost frames are first recovered from the FEC data in the following packet
+    fec: bool,
+    // Fills in the missing frames FEC can't recover
+    loss_handler: Box<dyn LossHandler>,
+    // Frames missing in a row so far
+    missing_run: u32,
+    // Blends the decoded signal with the input and effects it around losses
+    mixer: Option<Mixer>,
+    // Missing frames filled in by the loss handler
+    concealed_frames: u64,
+    // Missing frames rebuilt from the FEC data of the packet after them
+    recovered_frames: u64,
+    // Packets that arrived too damaged to decode, and were concealed instead
+    undecodable_frames: u64,
+    encoded: Vec<u8>,
+    decoded: Vec<f32>,
+}
+
+impl Pipeline {
+    pub fn new(
+        encoder: OpusEncoder,
+        decoder: Decoder,
+        channel: NetworkChannel,
+        frame_duration: FrameDuration,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            encoder,
+            decoder,
+            channel,
+            frame_duration,
+            sample_rate,
+            next_sequence: 0,
+            fec: false,
+            loss_handler: LossStrategy::default().build(),
+            missing_run: 0,
+            mixer: None,
+            concealed_frames: 0,
+            recovered_frames: 0,
+            undecodable_frames: 0,
+            encoded: vec![0u8; frame_duration.max_packet_size()],
+            // Sized for the longest possible packet, not just our own frames, so any
+            // packet that makes it through the network can be decoded
+            decoded: vec![0f32; FrameDuration::MAX.frame_size(sample_rate) * CHANNELS],
+        }
+    }
+
+    // Turns on Opus in-band FEC, telling the encoder how much loss to expect so it
+    // can decide how much redundancy to spend bits on
+    pub fn with_fec(mut self, expected_loss_percent: i32) -> Result<Self, anyhow::Error> {
+        self.encoder.set_inband_fec(true)?;
+        self.encoder.set_packet_loss_perc(expected_loss_percent)?;
+        self.fec = true;
+        Ok(self)
+    }
+
+    // Replaces Opus concealment with another way of filling in for lost packets
+    pub fn with_loss_handler(mut self, loss_handler: Box<dyn LossHandler>) -> Self {
+        self.loss_handler = loss_handler;
+        self
+    }
+
+    // Switches loss handling while running
+    pub fn set_loss_handler(&mut self, loss_handler: Box<dyn LossHandler>) {
+        self.loss_handler = loss_handler;
+        self.missing_run = 0;
+    }
+
+    // Mixes the decoded signal with the input after the decoder. The encoder's
+    // lookahead delays the decoded signal, so the input is delayed to match.
+    pub fn with_mixer(mut self, mixer: Mixer) -> Result<Self, anyhow::Error> {
+        let lookahead = self.encoder.lookahead()?;
+        self.mixer = Some(mixer.with_dry_delay(lookahead));
+        Ok(self)
+    }
+
+    // For changing the mix while running
+    pub fn mixer_mut(&mut self) -> Option<&mut Mixer> {
+        self.mixer.as_mut()
+    }
+
+    // Sends one interleaved frame and appends whatever became due for playout to `output`
+    pub fn process(&mut self, frame: &[f32], output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        let now_us = self.next_sequence * self.frame_duration_us();
+        if let Some(mixer) = &mut self.mixer {
+            mixer.push_dry(frame);
+        }
+
+        let encoded_len = self.encoder.encode_float(frame, &mut self.encoded)?;
+        let mut payload = self.channel.payload_buffer();
+        payload.extend_from_slice(&self.encoded[..encoded_len]);
+        self.channel.send(self.next_sequence, payload, now_us);
+        self.next_sequence += 1;
+
+        self.advance(now_us, output)
+    }
+
+    // Keeps the clock running without sending anything new until every frame sent so
+    // far has either been played or given up on
+    pub fn flush(&mut self, output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        let mut now_us = self.next_sequence * self.frame_duration_us();
+        while self.channel.next_sequence() < self.next_sequence {
+            self.advance(now_us, output)?;
+            now_us += self.frame_duration_us();
+        }
+        Ok(())
+    }
+
+    // Samples per channel in each frame
+    pub fn frame_size(&self) -> usize {
+        self.frame_duration.frame_size(self.sample_rate)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn frame_duration_us(&self) -> u64 {
+        self.frame_duration.duration_us()
+    }
+
+    pub fn channel(&self) -> &NetworkChannel {
+        &self.channel
+    }
+
+    // For changing the network conditions while running
+    pub fn channel_mut(&mut self) -> &mut NetworkChannel {
+        &mut self.channel
+    }
+
+    // For changing encoder settings such as the bitrate while running
+    pub fn encoder_mut(&mut self) -> &mut OpusEncoder {
+        &mut self.encoder
+    }
+
+    // What happened to the frames sent so far
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            channel: self.channel.stats(),
+            concealed_frames: self.concealed_frames,
+            recovered_frames: self.recovered_frames,
+            corrupted_packets: self.channel.network().corrupted_packets(),
+            undecodable_frames: self.undecodable_frames,
+        }
+    }
+
+    fn advance(&mut self, now_us: u64, output: &mut Vec<f32>) -> Result<(), anyhow::Error> {
+        while let Some(playout) = self.channel.pop(now_us) {
+            // Whether this frame's own packet didn't get through intact
+            let mut lost = true;
+            let decoded_len = match playout {
+                Playout::Packet(payload) => {
+                    // An empty packet would ask for concealment of the whole buffer
+                    let decoded_len = if payload.is_empty() {
+                        None
+                    } else {
+                        self.decoder
+                            .decode_float(&payload, &mut self.decoded, false)
+                            .ok()
+                    };
+                    // A damaged TOC byte can leave a packet that still decodes, but
+                    // to a different duration, which would throw the timing off
+                    let frame_size = self.frame_size();
+                    let decoded_len = match decoded_len {
+                        Some(decoded_len) if decoded_len == frame_size => {
+                            lost = false;
+                            self.missing_run = 0;
+                            self.loss_handler
+                                .received(&payload, &self.decoded[..decoded_len * CHANNELS]);
+                            decoded_len
+                        }
+                        _ => {
+                            self.undecodable_frames += 1;
+                            self.decode_missing()
+                        }
+                    };
+                    self.channel.recycle(payload);
+                    decoded_len
+                }
+                Playout::Missing => self.decode_missing(),
+            };
+
+            let decoded = &mut self.decoded[..decoded_len * CHANNELS];
+            if let Some(mixer) = &mut self.mixer {
+                // The frame just played is the one before the next in line
+                let sequence = self.channel.next_sequence() - 1;
+                let position = sequence * self.frame_duration.frame_size(self.sample_rate) as u64;
+                mixer.process(position, decoded, lost);
+            }
+            output.extend_from_slice(decoded);
+        }
+        Ok(())
+    }
+
+    // Fills in a lost or late frame so the output keeps its length, always one frame
+    fn decode_missing(&mut self) -> usize {
+        let frame_len = self.frame_size() * CHANNELS;
+        let frame = &mut self.decoded[..frame_len];
+
+        if self.fec {
+            if let Some(next_packet) = self
+                .channel
+                .peek_next()
+                .filter(|packet| !packet.payload.is_empty())
+            {
+                // Corrupted FEC data may not decode, leaving concealment to fill in
+                if let Ok(decoded_len) =
+                    self.decoder.decode_float(&next_packet.payload, frame, true)
+                {
+                    self.missing_run = 0;
+                    self.recovered_frames += 1;
+                    return decoded_len;
+                }
+            }
+        }
+
+        self.missing_run += 1;
+        self.concealed_frames += 1;
+        // A loss handler that fails falls back to Opus concealment, and to a silent
+        // frame if even that fails, rather than ending the run
+        if self
+            .loss_handler
+            .conceal(&mut self.decoder, frame, self.missing_run)
+            .is_err()
+            && self.decoder.decode_float(&[], frame, false).is_err()
+        {
+            frame.fill(0.0);
+        }
+        self.frame_size()
+    }
+}
+
+// One line per thing worth mentioning, without a trailing newline
+impl fmt::Display for PipelineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\n{} frames concealed, {} recovered with FEC",
+            self.channel, self.concealed_frames, self.recovered_frames
+        )?;
+        if self.corrupted_packets > 0 {
+            write!(
+                f,
+                "\n{} packets corrupted, {} too badly to decode",
+                self.corrupted_packets, self.undecodable_frames
+            )?;
+        }
+        Ok(())
+    }
+}
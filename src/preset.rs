@@ -0,0 +1,305 @@
+use crate::automation::NetworkAutomation;
+use crate::bottleneck::{Bottleneck, QueueDiscipline};
+use crate::codec::{ApplicationMode, CodecConfig, FrameDuration, MaxBandwidth, Signal, VbrMode};
+use crate::corruption::Corruption;
+use crate::jitter_buffer::{JitterBuffer, JitterBufferMode};
+use crate::loss_model::{GilbertElliott, LossModel};
+use crate::mixer::MixConfig;
+use crate::network_simulator::{Delivery, NetworkSimulator};
+use crate::step_sequencer::StepSequencer;
+use crate::trace::NetworkTrace;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// A named set of network and codec conditions, stored as TOML
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub network: NetworkConfig,
+    pub codec: CodecConfig,
+    // Dry/wet balance and effects around lost packets, after the decoder
+    #[serde(default, skip_serializing_if = "MixConfig::is_default")]
+    pub mix: MixConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkConfig {
+    pub loss: LossModel,
+    pub latency_us: u64,
+    pub jitter_us: u64,
+    pub jitter_buffer: JitterBufferMode,
+    // Fixes the random losses and delays, otherwise every run is different
+    pub seed: Option<u64>,
+    // Curves the loss, latency and jitter follow over the run, replacing the fixed
+    // values above while they're set
+    #[serde(default, skip_serializing_if = "NetworkAutomation::is_empty")]
+    pub automation: NetworkAutomation,
+    // Limited-rate link with a finite queue, so loss and delay come from congestion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bottleneck: Option<Bottleneck>,
+    // Duplicated and reordered packets
+    #[serde(default, skip_serializing_if = "Delivery::is_disabled")]
+    pub delivery: Delivery,
+    // Drops, freezes and repeats following a pattern at a given tempo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequencer: Option<StepSequencer>,
+    // Bit errors and other damage to the packets that arrive
+    #[serde(default, skip_serializing_if = "Corruption::is_disabled")]
+    pub corruption: Corruption,
+    // CSV log or pcap of a real stream to replay instead of the loss, latency,
+    // jitter and automation settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<PathBuf>,
+}
+
+impl NetworkConfig {
+    // Checks every setting is in range, so a hand-written preset or one built in
+    // code fails with a clear message rather than misbehaving or panicking later
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        self.loss.validate()?;
+        if let JitterBufferMode::Adaptive {
+            min_depth_us,
+            max_depth_us,
+        } = self.jitter_buffer
+        {
+            if min_depth_us > max_depth_us {
+                return Err(anyhow::Error::msg(format!(
+                    "Jitter buffer min_depth_us ({min_depth_us}) is above max_depth_us ({max_depth_us})"
+                )));
+            }
+        }
+        if let Some(bottleneck) = &self.bottleneck {
+            if bottleneck.rate_bps == 0 {
+                return Err(anyhow::Error::msg("Bottleneck rate_bps must be above 0"));
+            }
+            match bottleneck.discipline {
+                QueueDiscipline::DropTail => {}
+                QueueDiscipline::Red {
+                    min_threshold_bytes,
+                    max_threshold_bytes,
+                    max_probability,
+                } => {
+                    check_probability("RED max_probability", max_probability)?;
+                    if min_threshold_bytes > max_threshold_bytes {
+                        return Err(anyhow::Error::msg(format!(
+                            "RED min_threshold_bytes ({min_threshold_bytes}) is above max_threshold_bytes ({max_threshold_bytes})"
+                        )));
+                    }
+                }
+                QueueDiscipline::Codel { interval_us, .. } => {
+                    if interval_us == 0 {
+                        return Err(anyhow::Error::msg("CoDel interval_us must be above 0"));
+                    }
+                }
+            }
+            if let Some(shaper) = &bottleneck.shaper {
+                if shaper.rate_bps == 0 {
+                    return Err(anyhow::Error::msg("Shaper rate_bps must be above 0"));
+                }
+            }
+        }
+        check_probability("duplicate_probability", self.delivery.duplicate_probability)?;
+        check_probability("reorder_probability", self.delivery.reorder_probability)?;
+        if let Some(sequencer) = &self.sequencer {
+            sequencer.validate()?;
+        }
+        let corruption = &self.corruption;
+        check_probability("bit_error_rate", corruption.bit_error_rate)?;
+        check_probability("truncate_probability", corruption.truncate_probability)?;
+        check_probability("zero_probability", corruption.zero_probability)?;
+        check_probability(
+            "byte_repeat_probability",
+            corruption.byte_repeat_probability,
+        )?;
+        Ok(())
+    }
+
+    pub fn build_network(&self) -> Result<NetworkSimulator, anyhow::Error> {
+        self.validate()?;
+        let mut network = NetworkSimulator::new(0.0, self.latency_us, self.jitter_us)
+            .with_loss_model(self.loss.clone())
+            .with_automation(self.automation.clone());
+        if let Some(seed) = self.seed {
+            network = network.with_seed(seed);
+        }
+        if let Some(bottleneck) = &self.bottleneck {
+            network = network.with_bottleneck(bottleneck.clone());
+        }
+        if !self.delivery.is_disabled() {
+            network = network.with_delivery(self.delivery);
+        }
+        if let Some(sequencer) = &self.sequencer {
+            network = network.with_sequencer(sequencer.clone());
+        }
+        if !self.corruption.is_disabled() {
+            network = network.with_corruption(self.corruption.clone());
+        }
+        if let Some(path) = &self.trace {
+            network = network.with_trace(NetworkTrace::load(path)?);
+        }
+        Ok(network)
+    }
+
+    pub fn build_jitter_buffer(&self, frame_duration_us: u64) -> JitterBuffer {
+        JitterBuffer::new(self.jitter_buffer, frame_duration_us)
+    }
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            description: "Heavy independent loss on an otherwise fast link".to_string(),
+            network: NetworkConfig {
+                loss: LossModel::Bernoulli {
+                    loss_probability: 0.5,
+                },
+                latency_us: 10,
+                jitter_us: 5,
+                jitter_buffer: JitterBufferMode::Fixed { depth_us: 40_000 },
+                seed: None,
+                automation: NetworkAutomation::default(),
+                bottleneck: None,
+                delivery: Delivery::default(),
+                sequencer: None,
+                corruption: Corruption::default(),
+                trace: None,
+            },
+            codec: CodecConfig::default(),
+            mix: MixConfig::default(),
+        }
+    }
+}
+
+impl Preset {
+    // Looks up a built-in preset by name, falling back to reading a TOML file
+    pub fn load(name_or_path: &str) -> Result<Self, anyhow::Error> {
+        if let Some(preset) = builtin_presets()
+            .into_iter()
+            .find(|preset| preset.name == name_or_path)
+        {
+            return Ok(preset);
+        }
+
+        let contents = std::fs::read_to_string(name_or_path).map_err(|err| {
+            anyhow::Error::msg(format!(
+                "'{name_or_path}' is neither a built-in preset nor a readable file: {err}"
+            ))
+        })?;
+        let preset: Preset = toml::from_str(&contents)?;
+        preset
+            .network
+            .validate()
+            .map_err(|err| anyhow::Error::msg(format!("Preset {name_or_path}: {err}")))?;
+        Ok(preset)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+fn check_probability(name: &str, probability: f64) -> Result<(), anyhow::Error> {
+    if (0.0..=1.0).contains(&probability) {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "{name} must be between 0 and 1, got {probability}"
+        )))
+    }
+}
+
+pub fn builtin_presets() -> Vec<Preset> {
+    vec![
+        Preset::default(),
+        Preset {
+            name: "hotel-wifi".to_string(),
+            description: "Crowded wifi with frequent short dropouts and wandering delay"
+                .to_string(),
+            network: NetworkConfig {
+                loss: LossModel::GilbertElliott(GilbertElliott::from_average_loss(0.08, 3.0)),
+                latency_us: 40_000,
+                jitter_us: 80_000,
+                jitter_buffer: JitterBufferMode::Adaptive {
+                    min_depth_us: 40_000,
+                    max_depth_us: 200_000,
+                },
+                seed: None,
+                automation: NetworkAutomation::default(),
+                bottleneck: None,
+                delivery: Delivery::default(),
+                sequencer: None,
+                corruption: Corruption::default(),
+                trace: None,
+            },
+            codec: CodecConfig {
+                application: ApplicationMode::Voip,
+                bitrate: Some(24_000),
+                signal: Signal::Voice,
+                frame_ms: FrameDuration::Ms20,
+                fec: true,
+                ..CodecConfig::default()
+            },
+            mix: MixConfig::default(),
+        },
+        Preset {
+            name: "lte-handover".to_string(),
+            description: "Mostly clean mobile link with long outages when switching cells"
+                .to_string(),
+            network: NetworkConfig {
+                loss: LossModel::GilbertElliott(GilbertElliott::new(0.01, 0.1, 0.005, 0.9)),
+                latency_us: 50_000,
+                jitter_us: 30_000,
+                jitter_buffer: JitterBufferMode::Fixed { depth_us: 90_000 },
+                seed: None,
+                automation: NetworkAutomation::default(),
+                bottleneck: None,
+                delivery: Delivery::default(),
+                sequencer: None,
+                corruption: Corruption::default(),
+                trace: None,
+            },
+            codec: CodecConfig {
+                application: ApplicationMode::Audio,
+                bitrate: Some(32_000),
+                vbr: VbrMode::Vbr,
+                frame_ms: FrameDuration::Ms20,
+                fec: true,
+                ..CodecConfig::default()
+            },
+            mix: MixConfig::default(),
+        },
+        Preset {
+            name: "satellite".to_string(),
+            description: "Very long delay, light steady loss and a starved bitrate".to_string(),
+            network: NetworkConfig {
+                loss: LossModel::Bernoulli {
+                    loss_probability: 0.03,
+                },
+                latency_us: 600_000,
+                jitter_us: 20_000,
+                jitter_buffer: JitterBufferMode::Fixed { depth_us: 640_000 },
+                seed: None,
+                automation: NetworkAutomation::default(),
+                bottleneck: None,
+                delivery: Delivery::default(),
+                sequencer: None,
+                corruption: Corruption::default(),
+                trace: None,
+            },
+            codec: CodecConfig {
+                application: ApplicationMode::Voip,
+                bitrate: Some(12_000),
+                vbr: VbrMode::Cbr,
+                complexity: Some(4),
+                max_bandwidth: MaxBandwidth::Wideband,
+                frame_ms: FrameDuration::Ms40,
+                ..CodecConfig::default()
+            },
+            mix: MixConfig::default(),
+        },
+    ]
+}
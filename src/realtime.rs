@@ -0,0 +1,286 @@
+use crate::audio_file;
+use crate::channel_mapper;
+use crate::frame_accumulator::FrameAccumulator;
+use crate::pipeline::{Pipeline, CHANNELS};
+use crate::resampler::Resampler;
+use cpal::{FromSample, Sample};
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// How long the processing thread waits before checking the ring buffer again
+// when it's found it empty
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+type WavWriter = hound::WavWriter<BufWriter<File>>;
+
+// Everything between the audio coming in and the audio going out
+pub struct Processor {
+    // Channels in the incoming audio, mapped to stereo before anything else
+    pub input_channels: usize,
+    // Input rate to codec rate
+    pub input_resampler: Resampler,
+    pub accumulator: FrameAccumulator,
+    pub pipeline: Pipeline,
+    // Codec rate to output rate
+    pub output_resampler: Resampler,
+}
+
+impl Processor {
+    fn process(&mut self, stereo: &[f32]) -> Result<Vec<f32>, anyhow::Error> {
+        let mut resampled = Vec::new();
+        self.input_resampler.process(stereo, &mut resampled);
+        for sample in resampled {
+            self.accumulator.push(sample);
+        }
+
+        // Encode, send and decode every complete interleaved frame
+        let mut decoded = Vec::new();
+        while let Some(frame) = self.accumulator.next_frame() {
+            self.pipeline.process(frame, &mut decoded)?;
+        }
+
+        let mut output = Vec::new();
+        self.output_resampler.process(&decoded, &mut output);
+        Ok(output)
+    }
+
+    // Plays out whatever is still in flight once the input has stopped
+    fn finish(&mut self) -> Result<Vec<f32>, anyhow::Error> {
+        let mut decoded = Vec::new();
+        self.pipeline.flush(&mut decoded)?;
+
+        let mut output = Vec::new();
+        self.output_resampler.process(&decoded, &mut output);
+        self.output_resampler.flush(&mut output);
+        Ok(output)
+    }
+}
+
+// Where the workers send audio. Any of them can be left out.
+#[derive(Default)]
+pub struct Sinks {
+    // Recording of the processed signal, at the output rate
+    pub wet: Option<WavWriter>,
+    // Recording of the input as it came in, mapped to stereo
+    pub dry: Option<WavWriter>,
+    // Ring buffer feeding an output device, read by a `MonitorTap`
+    pub monitor: Option<rtrb::Producer<f32>>,
+}
+
+// The input callback's end of the chain. Pushing never blocks or allocates, so
+// it's safe to call on the audio thread.
+pub struct CallbackTap {
+    producer: rtrb::Producer<f32>,
+    overruns: Arc<AtomicU64>,
+}
+
+impl CallbackTap {
+    // Queues a callback's worth of samples for processing. If the processing
+    // thread has fallen so far behind that they don't fit, they're dropped and
+    // counted rather than waited for.
+    pub fn push<T>(&mut self, data: &[T])
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        match self.producer.write_chunk_uninit(data.len()) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(data.iter().map(|&sample| f32::from_sample(sample)));
+            }
+            Err(_) => {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// The output callback's end of a live chain, playing whatever the workers have
+// processed. Like `CallbackTap` it never blocks or allocates.
+pub struct MonitorTap {
+    consumer: rtrb::Consumer<f32>,
+    // Most samples allowed to wait in the ring. If the input device runs a little
+    // faster than the output, anything beyond this is skipped so the delay stays put.
+    max_buffered: usize,
+    underruns: Arc<AtomicU64>,
+}
+
+impl MonitorTap {
+    // `max_buffered` should be a whole number of frames of the output's channels
+    pub fn new(capacity: usize, max_buffered: usize) -> (rtrb::Producer<f32>, MonitorTap) {
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+        let tap = MonitorTap {
+            consumer,
+            max_buffered,
+            underruns: Arc::new(AtomicU64::new(0)),
+        };
+        (producer, tap)
+    }
+
+    // Callbacks that found less audio ready than they needed
+    pub fn underruns(&self) -> Arc<AtomicU64> {
+        self.underruns.clone()
+    }
+
+    pub fn pull<T>(&mut self, data: &mut [T])
+    where
+        T: Sample + FromSample<f32>,
+    {
+        // Only whole stereo frames are skipped or played, so the channels never swap
+        let excess = whole_frames(self.consumer.slots().saturating_sub(self.max_buffered));
+        if let Ok(chunk) = self.consumer.read_chunk(excess) {
+            chunk.commit_all();
+        }
+
+        let available = whole_frames(self.consumer.slots().min(data.len()));
+        let (ready, missing) = data.split_at_mut(available);
+        if let Ok(chunk) = self.consumer.read_chunk(available) {
+            let (first, second) = chunk.as_slices();
+            for (sample_out, &sample) in ready.iter_mut().zip(first.iter().chain(second)) {
+                *sample_out = T::from_sample(sample);
+            }
+            chunk.commit_all();
+        }
+        if !missing.is_empty() {
+            missing.fill(T::EQUILIBRIUM);
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// The processing and recording threads, which run until the tap is dropped
+pub struct Workers {
+    processor: JoinHandle<Result<Processor, anyhow::Error>>,
+    recorders: Vec<JoinHandle<Result<(), anyhow::Error>>>,
+    overruns: Arc<AtomicU64>,
+}
+
+impl Workers {
+    // Starts the threads, with room in the input ring buffer for `ring_len` samples
+    pub fn spawn(processor: Processor, sinks: Sinks, ring_len: usize) -> (CallbackTap, Workers) {
+        let (producer, consumer) = rtrb::RingBuffer::new(ring_len);
+        let overruns = Arc::new(AtomicU64::new(0));
+
+        let mut recorders = Vec::new();
+        let mut spawn_recorder = |writer: Option<WavWriter>| {
+            writer.map(|writer| {
+                let (sender, receiver) = mpsc::channel();
+                recorders.push(std::thread::spawn(move || record(writer, receiver)));
+                sender
+            })
+        };
+        let outputs = Outputs {
+            wet: spawn_recorder(sinks.wet),
+            dry: spawn_recorder(sinks.dry),
+            monitor: sinks.monitor,
+            overruns: overruns.clone(),
+        };
+        let processor = std::thread::spawn(move || process(processor, consumer, outputs));
+
+        let tap = CallbackTap {
+            producer,
+            overruns: overruns.clone(),
+        };
+        let workers = Workers {
+            processor,
+            recorders,
+            overruns,
+        };
+        (tap, workers)
+    }
+
+    // Waits for everything pushed so far to be processed and recorded, once the
+    // tap has been dropped along with the stream that owned it. Returns the
+    // processor, for its stats, and how many times audio had to be dropped
+    // because a ring buffer was full.
+    pub fn join(self) -> Result<(Processor, u64), anyhow::Error> {
+        let processor = self
+            .processor
+            .join()
+            .map_err(|_| anyhow::Error::msg("Processing thread panicked"))??;
+        for recorder in self.recorders {
+            recorder
+                .join()
+                .map_err(|_| anyhow::Error::msg("Recording thread panicked"))??;
+        }
+        Ok((processor, self.overruns.load(Ordering::Relaxed)))
+    }
+}
+
+struct Outputs {
+    wet: Option<mpsc::Sender<Vec<f32>>>,
+    dry: Option<mpsc::Sender<Vec<f32>>>,
+    monitor: Option<rtrb::Producer<f32>>,
+    overruns: Arc<AtomicU64>,
+}
+
+impl Outputs {
+    fn send_wet(&mut self, samples: Vec<f32>) {
+        if let Some(monitor) = &mut self.monitor {
+            // Whole stereo frames only, or the channels would swap from here on
+            let writable = whole_frames(monitor.slots().min(samples.len()));
+            let (written, _) = monitor.push_partial_slice(&samples[..writable]);
+            if written.len() < samples.len() {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // A recorder only goes away if it failed, which `join` reports
+        if let Some(wet) = &self.wet {
+            wet.send(samples).ok();
+        }
+    }
+}
+
+fn process(
+    mut processor: Processor,
+    mut consumer: rtrb::Consumer<f32>,
+    mut outputs: Outputs,
+) -> Result<Processor, anyhow::Error> {
+    loop {
+        let available = consumer.slots();
+        if available == 0 {
+            // Only stop once the callback side is gone and everything it sent is done
+            if consumer.is_abandoned() && consumer.is_empty() {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        // Whole frames of input only, so channels don't get out of step
+        let available = available - available % processor.input_channels;
+        let chunk = consumer.read_chunk(available)?;
+        let (first, second) = chunk.as_slices();
+        let mut input = first.to_vec();
+        input.extend_from_slice(second);
+        chunk.commit_all();
+
+        let stereo = channel_mapper::to_stereo(&input, processor.input_channels);
+        let output = processor.process(&stereo)?;
+        if let Some(dry) = &outputs.dry {
+            dry.send(stereo).ok();
+        }
+        outputs.send_wet(output);
+    }
+
+    let output = processor.finish()?;
+    outputs.send_wet(output);
+    Ok(processor)
+}
+
+fn record(mut writer: WavWriter, receiver: mpsc::Receiver<Vec<f32>>) -> Result<(), anyhow::Error> {
+    for samples in receiver {
+        audio_file::write_samples(&mut writer, &samples)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+// Rounds a count of interleaved samples down to whole stereo frames
+fn whole_frames(samples: usize) -> usize {
+    samples - samples % CHANNELS
+}
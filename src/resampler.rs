@@ -0,0 +1,277 @@
+use std::f64::consts::PI;
+
+// Zero crossings of the sinc on each side of the centre tap. More gives a steeper
+// anti-aliasing filter at the cost of more taps per output sample.
+const ZERO_CROSSINGS: usize = 24;
+// Kernel table entries per zero crossing, interpolated linearly in between
+const TABLE_RESOLUTION: usize = 256;
+// Kaiser window shape, around 90 dB of stopband attenuation
+const KAISER_BETA: f64 = 9.0;
+// Keep the passband edge a little below Nyquist so the transition band fits
+const ROLLOFF: f64 = 0.95;
+
+// Streaming band-limited resampler for interleaved audio, using a Kaiser-windowed
+// sinc evaluated at the exact fractional position of every output sample. Input
+// can be pushed in chunks of any size.
+pub struct Resampler {
+    from_rate: u64,
+    to_rate: u64,
+    channels: usize,
+    // Filter cutoff relative to the input Nyquist frequency
+    cutoff: f64,
+    // Taps needed on each side of an output position, in input frames
+    half_width: usize,
+    kernel: Vec<f64>,
+    // Buffered input, where frame `h` is input frame `consumed_frames - half_width + h`
+    history: Vec<f32>,
+    consumed_frames: u64,
+    input_frames: u64,
+    output_frames: u64,
+}
+
+impl Resampler {
+    // Both rates have to be above 0, which callers check where rates come in from
+    // files or the command line
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        assert!(
+            from_rate > 0 && to_rate > 0,
+            "can't resample from {from_rate} Hz to {to_rate} Hz"
+        );
+        let cutoff = (to_rate as f64 / from_rate as f64).min(1.0) * ROLLOFF;
+        let half_width = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+
+        let kernel = (0..=ZERO_CROSSINGS * TABLE_RESOLUTION + 1)
+            .map(|index| {
+                let x = index as f64 / TABLE_RESOLUTION as f64;
+                sinc(x) * kaiser(x / ZERO_CROSSINGS as f64)
+            })
+            .collect();
+
+        Self {
+            from_rate: from_rate as u64,
+            to_rate: to_rate as u64,
+            channels,
+            cutoff,
+            half_width,
+            kernel,
+            // Start with silence before the first sample so output frame 0 lines up
+            // with input frame 0 instead of being delayed by the filter length
+            history: vec![0.0; half_width * channels],
+            consumed_frames: 0,
+            input_frames: 0,
+            output_frames: 0,
+        }
+    }
+
+    pub fn to_rate(&self) -> u32 {
+        self.to_rate as u32
+    }
+
+    // Input frames that have to arrive after a position before the output frame
+    // there can be computed
+    pub fn lookahead_frames(&self) -> usize {
+        if self.is_passthrough() {
+            0
+        } else {
+            self.half_width + 1
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.from_rate == self.to_rate
+    }
+
+    // Pushes interleaved input and appends every output frame that can be computed
+    // so far to `output`
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        self.history.extend_from_slice(input);
+        self.input_frames += (input.len() / self.channels) as u64;
+        // Stops by itself at the first output frame whose taps haven't all arrived
+        self.drain_until(output, u64::MAX);
+    }
+
+    // Pads the end with silence so the tail of the input comes out, for a total of
+    // exactly input length * to_rate / from_rate frames
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            return;
+        }
+
+        let padding = vec![0.0; (self.half_width + 1) * self.channels];
+        self.history.extend_from_slice(&padding);
+        let total_output_frames = (self.input_frames * self.to_rate).div_ceil(self.from_rate);
+        self.drain_until(output, total_output_frames);
+    }
+
+    // Converts a whole buffer in one go
+    pub fn resample_all(input: &[f32], from_rate: u32, to_rate: u32, channels: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(from_rate, to_rate, channels);
+        let mut output = Vec::with_capacity(
+            (input.len() as u64 * to_rate as u64 / from_rate as u64) as usize + channels,
+        );
+        resampler.process(input, &mut output);
+        resampler.flush(&mut output);
+        output
+    }
+
+    fn drain_until(&mut self, output: &mut Vec<f32>, total_output_frames: u64) {
+        while self.output_frames < total_output_frames {
+            // Exact position of this output frame in input frames, as integer and fraction
+            let position = self.output_frames * self.from_rate;
+            let whole = position / self.to_rate;
+            let fraction = (position % self.to_rate) as f64 / self.to_rate as f64;
+
+            // Index into history, which has `half_width` frames of lead-in silence
+            let centre = (whole - self.consumed_frames) as usize + self.half_width;
+            let first = centre + 1 - self.half_width;
+            let last = centre + self.half_width;
+            if (last + 1) * self.channels > self.history.len() {
+                break;
+            }
+
+            for channel in 0..self.channels {
+                let mut sum = 0.0;
+                for frame in first..=last {
+                    let distance = frame as f64 - centre as f64 - fraction;
+                    sum +=
+                        self.history[frame * self.channels + channel] as f64 * self.tap(distance);
+                }
+                output.push((sum * self.cutoff) as f32);
+            }
+            self.output_frames += 1;
+        }
+
+        // Drop input that no future output frame reaches back to
+        let next_whole = self.output_frames * self.from_rate / self.to_rate;
+        let removable = (next_whole - self.consumed_frames) as usize;
+        if removable > 0 {
+            self.history.drain(..removable * self.channels);
+            self.consumed_frames += removable as u64;
+        }
+    }
+
+    fn tap(&self, distance: f64) -> f64 {
+        let position = distance.abs() * self.cutoff * TABLE_RESOLUTION as f64;
+        let index = position as usize;
+        if index >= ZERO_CROSSINGS * TABLE_RESOLUTION {
+            return 0.0;
+        }
+        let fraction = position - index as f64;
+        self.kernel[index] + (self.kernel[index + 1] - self.kernel[index]) * fraction
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Kaiser window at `x` from the centre, where 1.0 is the edge
+fn kaiser(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+// Zeroth-order modified Bessel function of the first kind, by its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..50 {
+        term *= half_x / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| (2.0 * PI * frequency * frame as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let sum: f64 = samples
+            .iter()
+            .map(|&sample| sample as f64 * sample as f64)
+            .sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn same_rate_passes_through() {
+        let input = sine(440.0, 48_000, 1000);
+        assert_eq!(Resampler::resample_all(&input, 48_000, 48_000, 1), input);
+    }
+
+    #[test]
+    fn output_length_follows_the_ratio() {
+        let input = vec![0.0; 44_100 * 2];
+        let output = Resampler::resample_all(&input, 44_100, 48_000, 2);
+        assert_eq!(output.len(), 48_000 * 2);
+        let output = Resampler::resample_all(&input[..1001 * 2], 48_000, 16_000, 2);
+        assert_eq!(output.len(), 334 * 2);
+    }
+
+    #[test]
+    fn keeps_a_tone_in_the_passband() {
+        let input = sine(1000.0, 44_100, 44_100);
+        let output = Resampler::resample_all(&input, 44_100, 48_000, 1);
+        let expected = sine(1000.0, 48_000, 48_000);
+        // Away from the ends, where the filter runs into the padding
+        let error: Vec<f32> = output[1000..47_000]
+            .iter()
+            .zip(&expected[1000..47_000])
+            .map(|(output, expected)| output - expected)
+            .collect();
+        assert!(rms(&error) < 1e-3, "error {}", rms(&error));
+    }
+
+    #[test]
+    fn filters_out_what_the_new_rate_cant_hold() {
+        let input = sine(12_000.0, 48_000, 48_000);
+        let output = Resampler::resample_all(&input, 48_000, 16_000, 1);
+        assert!(rms(&output[1000..15_000]) < 1e-3);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't resample")]
+    fn rejects_a_rate_of_zero() {
+        Resampler::new(0, 48_000, 2);
+    }
+
+    #[test]
+    fn chunks_give_the_same_output_as_one_go() {
+        let input: Vec<f32> = sine(300.0, 44_100, 5000)
+            .into_iter()
+            .zip(sine(700.0, 44_100, 5000))
+            .flat_map(|(left, right)| [left, right])
+            .collect();
+        let whole = Resampler::resample_all(&input, 44_100, 48_000, 2);
+
+        let mut resampler = Resampler::new(44_100, 48_000, 2);
+        let mut chunked = Vec::new();
+        for chunk in input.chunks(2 * 317) {
+            resampler.process(chunk, &mut chunked);
+        }
+        resampler.flush(&mut chunked);
+        assert_eq!(chunked, whole);
+    }
+}
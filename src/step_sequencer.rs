@@ -0,0 +1,430 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Glitches on a musical grid instead of at random: a bar is split into steps at a
+// given tempo and time signature, and each lane's pattern says on which steps it
+// acts. Each step is decided once, so a hit drops, freezes or repeats every packet
+// sent during it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StepSequencer {
+    // Quarter notes per minute
+    pub bpm: f64,
+    pub beats_per_bar: u32,
+    // Note value of each beat in the time signature, e.g. 8 for 6/8
+    pub beat_unit: u32,
+    // Steps per quarter note, e.g. 4 for a grid of sixteenths
+    pub steps_per_beat: u32,
+    // Checked in order, the first lane hitting a step decides what happens on it
+    pub lanes: Vec<Lane>,
+    #[serde(skip)]
+    state: SequencerState,
+}
+
+// What a lane does on the steps it hits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepAction {
+    // Packets are lost
+    Drop,
+    // Every packet repeats the first one of the step, holding that sound
+    Freeze,
+    // Packets replay those of the last step that wasn't repeated, like a beat repeat
+    Repeat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lane {
+    pub action: StepAction,
+    pub pattern: Pattern,
+}
+
+// Chance of a lane hitting each step of a bar, repeating if the bar has more
+// steps. Written either as a grid, one character per step with `x` for a hit, `.`
+// or `-` for a rest and 1 to 9 for a 10% to 90% chance, or as a Euclidean rhythm
+// `e<hits>/<steps>` spreading the hits as evenly as possible, optionally rotated
+// later with `+<steps>`. Either can end in `@<probability>` to scale every step.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern {
+    text: String,
+    probabilities: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SequencerStats {
+    pub dropped_steps: u64,
+    pub frozen_steps: u64,
+    pub repeated_steps: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SequencerState {
+    step: Option<u64>,
+    action: Option<StepAction>,
+    // Payloads as sent during the current step, and during the last step that
+    // wasn't a repeat
+    current_packets: Vec<Vec<u8>>,
+    repeat_packets: Vec<Vec<u8>>,
+    frozen: Option<Vec<u8>>,
+    stats: SequencerStats,
+}
+
+// 120 BPM in 4/4 on a grid of sixteenths, with nothing on it yet
+impl Default for StepSequencer {
+    fn default() -> Self {
+        Self::new(120.0, 4, 4, 4)
+    }
+}
+
+impl StepSequencer {
+    pub fn new(bpm: f64, beats_per_bar: u32, beat_unit: u32, steps_per_beat: u32) -> Self {
+        Self {
+            bpm,
+            beats_per_bar,
+            beat_unit,
+            steps_per_beat,
+            lanes: Vec::new(),
+            state: SequencerState::default(),
+        }
+    }
+
+    pub fn with_lane(mut self, lane: Lane) -> Self {
+        self.lanes.push(lane);
+        self
+    }
+
+    pub fn stats(&self) -> SequencerStats {
+        self.state.stats
+    }
+
+    pub fn step_duration_us(&self) -> f64 {
+        60_000_000.0 / (self.bpm * self.steps_per_beat.max(1) as f64)
+    }
+
+    pub fn steps_per_bar(&self) -> u64 {
+        let quarter_steps =
+            (self.beats_per_bar as u64 * self.steps_per_beat as u64).saturating_mul(4);
+        quarter_steps.div_ceil(self.beat_unit.max(1) as u64).max(1)
+    }
+
+    // Rejects a tempo or meter that gives no sensible step length
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(self.bpm.is_finite() && self.bpm > 0.0) {
+            return Err(anyhow::Error::msg(format!(
+                "Step sequencer bpm must be above 0, got {}",
+                self.bpm
+            )));
+        }
+        if self.beats_per_bar == 0 || self.beat_unit == 0 || self.steps_per_beat == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "Step sequencer needs a meter and steps per beat above 0, got {}/{} with {} steps per beat",
+                self.beats_per_bar, self.beat_unit, self.steps_per_beat
+            )));
+        }
+        Ok(())
+    }
+
+    // Passes on the payload of a packet sent at `now_us` as the pattern says: as is,
+    // swapped for an earlier one, or not at all if it's dropped. Random numbers are
+    // only drawn at the start of each step.
+    pub fn apply<R: Rng>(&mut self, now_us: u64, payload: Vec<u8>, rng: &mut R) -> Option<Vec<u8>> {
+        let step = (now_us as f64 / self.step_duration_us()) as u64;
+        if self.state.step != Some(step) {
+            self.start_step(step, rng);
+        }
+
+        let state = &mut self.state;
+        let index = state.current_packets.len();
+        state.current_packets.push(payload.clone());
+        match state.action {
+            None => Some(payload),
+            Some(StepAction::Drop) => None,
+            Some(StepAction::Freeze) => Some(state.frozen.get_or_insert(payload).clone()),
+            Some(StepAction::Repeat) => match state.repeat_packets.len() {
+                // Nothing to repeat yet at the very start
+                0 => Some(payload),
+                len => Some(state.repeat_packets[index % len].clone()),
+            },
+        }
+    }
+
+    fn start_step<R: Rng>(&mut self, step: u64, rng: &mut R) {
+        let position = (step % self.steps_per_bar()) as usize;
+        let action = self
+            .lanes
+            .iter()
+            .find(|lane| lane.pattern.hits(position, rng))
+            .map(|lane| lane.action);
+
+        let state = &mut self.state;
+        // A run of repeats keeps replaying the same material
+        if state.action != Some(StepAction::Repeat) {
+            state.repeat_packets = std::mem::take(&mut state.current_packets);
+        }
+        state.current_packets.clear();
+        state.frozen = None;
+        state.step = Some(step);
+        state.action = action;
+        match action {
+            Some(StepAction::Drop) => state.stats.dropped_steps += 1,
+            Some(StepAction::Freeze) => state.stats.frozen_steps += 1,
+            Some(StepAction::Repeat) => state.stats.repeated_steps += 1,
+            None => {}
+        }
+    }
+}
+
+impl Pattern {
+    // Rolls whether the pattern hits the step at `position` in the bar. Certain
+    // hits and rests don't draw a random number.
+    fn hits<R: Rng>(&self, position: usize, rng: &mut R) -> bool {
+        let probability = self.probabilities[position % self.probabilities.len()];
+        if probability <= 0.0 {
+            false
+        } else if probability >= 1.0 {
+            true
+        } else {
+            rng.gen::<f64>() < probability
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (steps, scale) = match text.split_once('@') {
+            Some((steps, scale)) => {
+                let scale: f64 = scale
+                    .parse()
+                    .map_err(|_| format!("invalid probability '{scale}'"))?;
+                (steps, scale)
+            }
+            None => (text, 1.0),
+        };
+
+        let probabilities: Vec<f64> = match steps.strip_prefix('e') {
+            Some(euclidean) => parse_euclidean(euclidean)?,
+            None => steps
+                .chars()
+                .map(|step| match step {
+                    'x' | 'X' => Ok(1.0),
+                    '.' | '-' => Ok(0.0),
+                    '1'..='9' => Ok(step.to_digit(10).unwrap() as f64 / 10.0),
+                    _ => Err(format!(
+                        "unexpected '{step}' in pattern, use x for a hit, . for a rest or 1-9"
+                    )),
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        if probabilities.is_empty() {
+            return Err("a pattern needs at least one step".to_string());
+        }
+
+        Ok(Pattern {
+            text: text.to_string(),
+            probabilities: probabilities
+                .into_iter()
+                .map(|probability| (probability * scale).clamp(0.0, 1.0))
+                .collect(),
+        })
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.text
+    }
+}
+
+// "<action>:<pattern>", e.g. "drop:x...x..." or "repeat:e3/8+2@0.5"
+impl FromStr for Lane {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (action, pattern) = text
+            .split_once(':')
+            .ok_or_else(|| format!("expected <drop|freeze|repeat>:<pattern>, got '{text}'"))?;
+        let action = match action {
+            "drop" => StepAction::Drop,
+            "freeze" => StepAction::Freeze,
+            "repeat" => StepAction::Repeat,
+            _ => {
+                return Err(format!(
+                    "unknown step action '{action}', expected drop, freeze or repeat"
+                ))
+            }
+        };
+        Ok(Lane {
+            action,
+            pattern: pattern.parse()?,
+        })
+    }
+}
+
+// "<hits>/<steps>[+<rotation>]", spreading the hits evenly with the first on step 0
+fn parse_euclidean(text: &str) -> Result<Vec<f64>, String> {
+    let invalid = || format!("expected e<hits>/<steps>[+<rotation>], got 'e{text}'");
+    let (rhythm, rotation) = match text.split_once('+') {
+        Some((rhythm, rotation)) => (rhythm, rotation.parse().map_err(|_| invalid())?),
+        None => (text, 0),
+    };
+    let (hits, steps) = rhythm.split_once('/').ok_or_else(invalid)?;
+    let hits: usize = hits.parse().map_err(|_| invalid())?;
+    let steps: usize = steps.parse().map_err(|_| invalid())?;
+    if steps == 0 || hits > steps {
+        return Err(format!(
+            "can't fit {hits} hits in {steps} steps in 'e{text}'"
+        ));
+    }
+
+    Ok((0..steps)
+        .map(|step| {
+            let unrotated = (step + steps - rotation % steps) % steps;
+            if (unrotated * hits) % steps < hits {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn probabilities(text: &str) -> Vec<f64> {
+        text.parse::<Pattern>().unwrap().probabilities
+    }
+
+    // A sequencer with one lane at 120 BPM, so each sixteenth lasts 125 ms
+    fn sequencer(lane: &str) -> StepSequencer {
+        StepSequencer::default().with_lane(lane.parse().unwrap())
+    }
+
+    // Sends two packets per step for `steps` steps, returning what comes out of each
+    fn run(sequencer: &mut StepSequencer, steps: u64) -> Vec<Option<u8>> {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..steps * 2)
+            .map(|index| {
+                let now_us = index * 62_500;
+                sequencer
+                    .apply(now_us, vec![index as u8], &mut rng)
+                    .map(|payload| payload[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_grids() {
+        assert_eq!(probabilities("x.-5"), vec![1.0, 0.0, 0.0, 0.5]);
+        assert_eq!(probabilities("x.5@0.5"), vec![0.5, 0.0, 0.25]);
+        assert!("x?".parse::<Pattern>().is_err());
+        assert!("".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn parses_euclidean_rhythms() {
+        assert_eq!(
+            probabilities("e3/8"),
+            vec![1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(
+            probabilities("e3/8+1"),
+            vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0]
+        );
+        assert!("e9/8".parse::<Pattern>().is_err());
+        assert!("e3".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn parses_lanes() {
+        let lane: Lane = "freeze:x...".parse().unwrap();
+        assert_eq!(lane.action, StepAction::Freeze);
+        assert!("stutter:x...".parse::<Lane>().is_err());
+        assert!("x...".parse::<Lane>().is_err());
+    }
+
+    #[test]
+    fn counts_steps_in_the_bar() {
+        let sequencer = StepSequencer::new(90.0, 6, 8, 4);
+        assert_eq!(sequencer.steps_per_bar(), 12);
+        assert_eq!(StepSequencer::default().steps_per_bar(), 16);
+        assert_eq!(StepSequencer::default().step_duration_us(), 125_000.0);
+    }
+
+    #[test]
+    fn rejects_a_tempo_or_meter_of_zero() {
+        assert!(StepSequencer::default().validate().is_ok());
+        assert!(StepSequencer::new(0.0, 4, 4, 4).validate().is_err());
+        assert!(StepSequencer::new(-120.0, 4, 4, 4).validate().is_err());
+        assert!(StepSequencer::new(f64::NAN, 4, 4, 4).validate().is_err());
+        assert!(StepSequencer::new(120.0, 0, 4, 4).validate().is_err());
+        assert!(StepSequencer::new(120.0, 4, 0, 4).validate().is_err());
+        assert!(StepSequencer::new(120.0, 4, 4, 0).validate().is_err());
+    }
+
+    #[test]
+    fn huge_meters_dont_overflow() {
+        let sequencer = StepSequencer::new(120.0, u32::MAX, 1, u32::MAX);
+        assert_eq!(sequencer.steps_per_bar(), u64::MAX);
+    }
+
+    #[test]
+    fn drops_whole_steps() {
+        let mut sequencer = sequencer("drop:.x");
+        let output = run(&mut sequencer, 4);
+        assert_eq!(
+            output,
+            vec![Some(0), Some(1), None, None, Some(4), Some(5), None, None]
+        );
+        assert_eq!(sequencer.stats().dropped_steps, 2);
+    }
+
+    #[test]
+    fn freezes_on_the_first_packet_of_a_step() {
+        let mut sequencer = sequencer("freeze:.x");
+        let output = run(&mut sequencer, 2);
+        assert_eq!(output, vec![Some(0), Some(1), Some(2), Some(2)]);
+        assert_eq!(sequencer.stats().frozen_steps, 1);
+    }
+
+    #[test]
+    fn repeats_the_last_step_that_wasnt_a_repeat() {
+        let mut sequencer = sequencer("repeat:.xx.");
+        let output = run(&mut sequencer, 4);
+        assert_eq!(
+            output,
+            vec![
+                Some(0),
+                Some(1),
+                Some(0),
+                Some(1),
+                Some(0),
+                Some(1),
+                Some(6),
+                Some(7)
+            ]
+        );
+        assert_eq!(sequencer.stats().repeated_steps, 2);
+    }
+
+    #[test]
+    fn first_lane_wins() {
+        let mut sequencer = sequencer("drop:.x").with_lane("freeze:xx".parse().unwrap());
+        let output = run(&mut sequencer, 2);
+        assert_eq!(output, vec![Some(0), Some(0), None, None]);
+    }
+}
@@ -0,0 +1,445 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Most sequence numbers missing in a row before a trace is taken to be damaged,
+// ten minutes of 20 ms packets
+const MAX_SEQUENCE_GAP: u64 = 30_000;
+// Most packets a trace can cover, about a day of 20 ms packets
+const MAX_TRACE_PACKETS: u64 = 5_000_000;
+
+// Loss and delay recorded for each packet of a real stream, replayed in place of
+// the simulator's random models. Packet n of our stream gets the fate of packet n
+// of the trace, starting over from the top if the audio outlasts the trace.
+#[derive(Debug, Clone)]
+pub struct NetworkTrace {
+    // One-way delay of each packet in sequence order, or None if it never arrived
+    delays_us: Vec<Option<u64>>,
+    // SSRC of the RTP stream picked out of a capture
+    ssrc: Option<u32>,
+}
+
+impl NetworkTrace {
+    // Reads a libpcap capture if the file starts like one, otherwise a CSV log
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read(path).map_err(|err| {
+            anyhow::Error::msg(format!("Can't read trace {}: {err}", path.display()))
+        })?;
+        let trace = match contents.get(..4) {
+            Some(magic) if pcap::is_pcap(magic) => Self::from_pcap(&contents)?,
+            Some(magic) if pcap::is_pcapng(magic) => {
+                return Err(anyhow::Error::msg(
+                    "pcapng captures aren't supported, convert with `editcap -F pcap in.pcapng out.pcap`",
+                ))
+            }
+            _ => Self::from_csv(&String::from_utf8(contents)?)?,
+        };
+        if trace.delays_us.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "Trace {} doesn't contain any packets",
+                path.display()
+            )));
+        }
+        Ok(trace)
+    }
+
+    // Rows of `sequence,send_us,arrival_us`, where an empty arrival or `lost` marks
+    // a lost packet. Sequence numbers missing from the log count as lost too, and a
+    // header row and `#` comments are skipped.
+    pub fn from_csv(contents: &str) -> Result<Self, anyhow::Error> {
+        let mut packets = Vec::new();
+        // The header, if any, is the first line that isn't blank or a comment
+        let mut first_row = true;
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let is_first_row = std::mem::replace(&mut first_row, false);
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let Ok(sequence) = fields[0].parse::<u64>() else {
+                if is_first_row {
+                    continue;
+                }
+                return Err(anyhow::Error::msg(format!(
+                    "Trace line {}: bad sequence number '{}'",
+                    line_number + 1,
+                    fields[0]
+                )));
+            };
+            let parse_time = |field: &str| {
+                field.parse::<u64>().map_err(|err| {
+                    anyhow::Error::msg(format!(
+                        "Trace line {}: bad time '{field}': {err}",
+                        line_number + 1
+                    ))
+                })
+            };
+            let send_us = parse_time(fields.get(1).copied().unwrap_or_default())?;
+            let delay_us = match fields.get(2).copied().unwrap_or_default() {
+                "" | "lost" => None,
+                arrival => Some(parse_time(arrival)?.saturating_sub(send_us)),
+            };
+            packets.push((sequence, delay_us));
+        }
+        Self::from_packets(packets)
+    }
+
+    // Takes the RTP stream with the most packets in a capture made at the receiving
+    // end. Send times come from the RTP timestamps and arrival times from the
+    // capture, and since the clocks aren't shared, delays are relative to the
+    // fastest packet.
+    pub fn from_pcap(contents: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut streams: HashMap<u32, Vec<pcap::RtpPacket>> = HashMap::new();
+        for packet in pcap::rtp_packets(contents)? {
+            streams.entry(packet.ssrc).or_default().push(packet);
+        }
+        // Ties go to the lowest SSRC, so the pick doesn't depend on hash order
+        let Some((ssrc, packets)) = streams
+            .into_iter()
+            .max_by_key(|(ssrc, packets)| (packets.len(), Reverse(*ssrc)))
+        else {
+            return Err(anyhow::Error::msg("No RTP packets found in the capture"));
+        };
+        // Unwrap the 16-bit sequence numbers and 32-bit timestamps in capture order
+        let first = &packets[0];
+        let (mut sequence, mut timestamp) = (first.sequence as i64, first.timestamp as i64);
+        let mut unwrapped = Vec::with_capacity(packets.len());
+        for packet in &packets {
+            sequence += (packet.sequence.wrapping_sub(sequence as u16) as i16) as i64;
+            timestamp += (packet.timestamp.wrapping_sub(timestamp as u32) as i32) as i64;
+            // Opus RTP timestamps always count at 48 kHz
+            let send_us = (timestamp - first.timestamp as i64) * 1_000_000 / 48_000;
+            let arrival_us = packet.captured_at_us as i64 - first.captured_at_us as i64;
+            unwrapped.push((sequence, arrival_us - send_us));
+        }
+
+        let first_sequence = unwrapped.iter().map(|&(sequence, _)| sequence).min();
+        let fastest = unwrapped.iter().map(|&(_, delay)| delay).min();
+        let (Some(first_sequence), Some(fastest)) = (first_sequence, fastest) else {
+            return Self::from_packets(Vec::new());
+        };
+        let mut trace = Self::from_packets(
+            unwrapped
+                .into_iter()
+                .map(|(sequence, delay)| {
+                    (
+                        (sequence - first_sequence) as u64,
+                        Some((delay - fastest) as u64),
+                    )
+                })
+                .collect(),
+        )?;
+        trace.ssrc = Some(ssrc);
+        Ok(trace)
+    }
+
+    // Lays packets out by sequence number, leaving gaps as losses and keeping the
+    // first copy of any duplicates. A jump in sequence numbers too big to be real
+    // loss is taken as a damaged log rather than allocated for.
+    fn from_packets(mut packets: Vec<(u64, Option<u64>)>) -> Result<Self, anyhow::Error> {
+        packets.sort_by_key(|&(sequence, _)| sequence);
+        packets.dedup_by_key(|&mut (sequence, _)| sequence);
+        let Some(&(first_sequence, _)) = packets.first() else {
+            return Ok(Self {
+                delays_us: Vec::new(),
+                ssrc: None,
+            });
+        };
+        for pair in packets.windows(2) {
+            let (from, to) = (pair[0].0, pair[1].0);
+            if to - from > MAX_SEQUENCE_GAP {
+                return Err(anyhow::Error::msg(format!(
+                    "Trace jumps from sequence {from} to {to}, more than {MAX_SEQUENCE_GAP} \
+                     packets lost in a row"
+                )));
+            }
+        }
+        let last_sequence = packets[packets.len() - 1].0;
+        if last_sequence - first_sequence >= MAX_TRACE_PACKETS {
+            return Err(anyhow::Error::msg(format!(
+                "Trace spans sequences {first_sequence} to {last_sequence}, more than \
+                 {MAX_TRACE_PACKETS} packets"
+            )));
+        }
+
+        let mut delays_us = vec![None; (last_sequence - first_sequence + 1) as usize];
+        for (sequence, delay_us) in packets {
+            delays_us[(sequence - first_sequence) as usize] = delay_us;
+        }
+        Ok(Self {
+            delays_us,
+            ssrc: None,
+        })
+    }
+
+    // How long packet `sequence` takes to arrive, or None if it gets lost
+    pub fn delay_us(&self, sequence: u64) -> Option<u64> {
+        self.delays_us[(sequence % self.delays_us.len() as u64) as usize]
+    }
+
+    // The RTP stream replayed, if the trace came from a capture
+    pub fn ssrc(&self) -> Option<u32> {
+        self.ssrc
+    }
+
+    pub fn packet_count(&self) -> usize {
+        self.delays_us.len()
+    }
+
+    pub fn loss_probability(&self) -> f32 {
+        let lost = self
+            .delays_us
+            .iter()
+            .filter(|delay| delay.is_none())
+            .count();
+        lost as f32 / self.delays_us.len() as f32
+    }
+}
+
+// Just enough of the libpcap format and the protocols underneath RTP to pull the
+// RTP headers and capture times out of a capture
+mod pcap {
+    const ETHERNET: u32 = 1;
+    const RAW_IP: u32 = 101;
+    const LINUX_COOKED: u32 = 113;
+    const NULL_LOOPBACK: u32 = 0;
+
+    pub struct RtpPacket {
+        pub sequence: u16,
+        pub timestamp: u32,
+        pub ssrc: u32,
+        pub captured_at_us: u64,
+    }
+
+    pub fn is_pcap(magic: &[u8]) -> bool {
+        matches!(
+            magic,
+            [0xd4, 0xc3, 0xb2, 0xa1]
+                | [0xa1, 0xb2, 0xc3, 0xd4]
+                | [0x4d, 0x3c, 0xb2, 0xa1]
+                | [0xa1, 0xb2, 0x3c, 0x4d]
+        )
+    }
+
+    pub fn is_pcapng(magic: &[u8]) -> bool {
+        magic == [0x0a, 0x0d, 0x0d, 0x0a]
+    }
+
+    pub fn rtp_packets(contents: &[u8]) -> Result<Vec<RtpPacket>, anyhow::Error> {
+        let truncated = || anyhow::Error::msg("Capture file is truncated");
+        let header = contents.get(..24).ok_or_else(truncated)?;
+        let little_endian = header[0] == 0xd4 || header[0] == 0x4d;
+        let nanoseconds =
+            header[..4] == [0x4d, 0x3c, 0xb2, 0xa1] || header[..4] == [0xa1, 0xb2, 0x3c, 0x4d];
+        let read_u32 = |bytes: &[u8]| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if little_endian {
+                u32::from_le_bytes(bytes)
+            } else {
+                u32::from_be_bytes(bytes)
+            }
+        };
+        let link_type = read_u32(&header[20..24]);
+        if ![ETHERNET, RAW_IP, LINUX_COOKED, NULL_LOOPBACK].contains(&link_type) {
+            return Err(anyhow::Error::msg(format!(
+                "Unsupported capture link type {link_type}"
+            )));
+        }
+
+        let mut packets = Vec::new();
+        let mut offset = 24;
+        while offset < contents.len() {
+            let record = contents.get(offset..offset + 16).ok_or_else(truncated)?;
+            let seconds = read_u32(&record[0..4]) as u64;
+            let fraction = read_u32(&record[4..8]) as u64;
+            let captured_len = read_u32(&record[8..12]) as usize;
+            let data = contents
+                .get(offset + 16..offset + 16 + captured_len)
+                .ok_or_else(truncated)?;
+            offset += 16 + captured_len;
+
+            let captured_at_us = seconds * 1_000_000
+                + if nanoseconds {
+                    fraction / 1000
+                } else {
+                    fraction
+                };
+            if let Some(packet) = link_payload(data, link_type)
+                .and_then(udp_payload)
+                .and_then(|payload| rtp_header(payload, captured_at_us))
+            {
+                packets.push(packet);
+            }
+        }
+        Ok(packets)
+    }
+
+    // The IP packet inside a link layer frame
+    fn link_payload(data: &[u8], link_type: u32) -> Option<&[u8]> {
+        match link_type {
+            ETHERNET => {
+                // Skip any VLAN tags
+                let mut offset = 12;
+                while data.get(offset..offset + 2)? == [0x81, 0x00] {
+                    offset += 4;
+                }
+                data.get(offset + 2..)
+            }
+            LINUX_COOKED => data.get(16..),
+            NULL_LOOPBACK => data.get(4..),
+            _ => Some(data),
+        }
+    }
+
+    fn udp_payload(ip: &[u8]) -> Option<&[u8]> {
+        const UDP: u8 = 17;
+        let (protocol, header_len) = match ip.first()? >> 4 {
+            4 => (*ip.get(9)?, ((ip[0] & 0x0f) as usize) * 4),
+            // Extension headers aren't followed, they don't show up on RTP streams
+            6 => (*ip.get(6)?, 40),
+            _ => return None,
+        };
+        if protocol != UDP {
+            return None;
+        }
+        ip.get(header_len + 8..)
+    }
+
+    fn rtp_header(payload: &[u8], captured_at_us: u64) -> Option<RtpPacket> {
+        let header = payload.get(..12)?;
+        let payload_type = header[1] & 0x7f;
+        // Version 2, and not RTCP sharing the port
+        if header[0] >> 6 != 2 || (72..=76).contains(&payload_type) {
+            return None;
+        }
+        Some(RtpPacket {
+            sequence: u16::from_be_bytes([header[2], header[3]]),
+            timestamp: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            ssrc: u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+            captured_at_us,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(trace: &NetworkTrace) -> Vec<Option<u64>> {
+        (0..trace.packet_count() as u64)
+            .map(|sequence| trace.delay_us(sequence))
+            .collect()
+    }
+
+    #[test]
+    fn reads_csv_rows() {
+        let trace =
+            NetworkTrace::from_csv("0,0,30000\n1,20000,lost\n2,40000,\n4,80000,95000\n").unwrap();
+        assert_eq!(
+            delays(&trace),
+            vec![Some(30_000), None, None, None, Some(15_000)]
+        );
+        assert_eq!(trace.loss_probability(), 0.6);
+        assert_eq!(trace.ssrc(), None);
+    }
+
+    #[test]
+    fn skips_a_header_after_comments_and_blank_lines() {
+        let csv = "# recorded on a train\n\nsequence,send_us,arrival_us\n# first packet\n0,0,10\n";
+        let trace = NetworkTrace::from_csv(csv).unwrap();
+        assert_eq!(delays(&trace), vec![Some(10)]);
+    }
+
+    #[test]
+    fn rejects_a_bad_row_after_the_first() {
+        let err = NetworkTrace::from_csv("0,0,10\nsequence,send_us,arrival_us\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+        assert!(NetworkTrace::from_csv("0,0,soon\n").is_err());
+    }
+
+    #[test]
+    fn rejects_sequence_jumps_too_big_to_be_loss() {
+        let err = NetworkTrace::from_csv("0,0,10\n18446744073709551615,0,10\n").unwrap_err();
+        assert!(err.to_string().contains("jumps"), "{err}");
+        let rows: String = (0..200)
+            .map(|row| format!("{},0,10\n", row * MAX_SEQUENCE_GAP))
+            .collect();
+        assert!(NetworkTrace::from_csv(&rows).is_err());
+    }
+
+    #[test]
+    fn loops_and_starts_at_the_first_sequence() {
+        let trace = NetworkTrace::from_csv("10,0,5\n11,0,\n12,0,7\n12,0,9\n").unwrap();
+        assert_eq!(trace.packet_count(), 3);
+        assert_eq!(trace.delay_us(0), Some(5));
+        assert_eq!(trace.delay_us(2), Some(7));
+        assert_eq!(trace.delay_us(4), None);
+    }
+
+    // A little-endian raw IP capture of RTP packets, as (ssrc, sequence, timestamp,
+    // capture time in us)
+    fn capture(packets: &[(u32, u16, u32, u64)]) -> Vec<u8> {
+        let mut contents = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        contents.extend_from_slice(&[0; 8]);
+        contents.extend_from_slice(&65535u32.to_le_bytes());
+        contents.extend_from_slice(&101u32.to_le_bytes());
+        for &(ssrc, sequence, timestamp, captured_at_us) in packets {
+            let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17];
+            ip.resize(20, 0);
+            ip.extend_from_slice(&[0; 8]);
+            ip.extend_from_slice(&[0x80, 111]);
+            ip.extend_from_slice(&sequence.to_be_bytes());
+            ip.extend_from_slice(&timestamp.to_be_bytes());
+            ip.extend_from_slice(&ssrc.to_be_bytes());
+            ip.extend_from_slice(&[0xfc; 20]);
+
+            contents.extend_from_slice(&((captured_at_us / 1_000_000) as u32).to_le_bytes());
+            contents.extend_from_slice(&((captured_at_us % 1_000_000) as u32).to_le_bytes());
+            contents.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+            contents.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+            contents.extend_from_slice(&ip);
+        }
+        contents
+    }
+
+    #[test]
+    fn replays_the_busiest_rtp_stream_of_a_capture() {
+        // 20 ms packets across a sequence number wrap, one lost and one 30 ms late,
+        // with a shorter stream mixed in
+        let contents = capture(&[
+            (7, 65534, 1000, 1_000_000),
+            (9, 5, 0, 1_005_000),
+            (7, 65535, 1960, 1_020_000),
+            (7, 1, 3880, 1_090_000),
+            (7, 2, 4840, 1_080_000),
+        ]);
+        assert!(pcap::is_pcap(&contents[..4]));
+        let trace = NetworkTrace::from_pcap(&contents).unwrap();
+        assert_eq!(trace.ssrc(), Some(7));
+        assert_eq!(
+            delays(&trace),
+            vec![Some(0), Some(0), None, Some(30_000), Some(0)]
+        );
+    }
+
+    #[test]
+    fn picks_the_lowest_ssrc_of_equally_busy_streams() {
+        let contents = capture(&[
+            (9, 0, 0, 0),
+            (4, 0, 0, 0),
+            (9, 1, 960, 20_000),
+            (4, 1, 960, 20_000),
+        ]);
+        for _ in 0..10 {
+            let trace = NetworkTrace::from_pcap(&contents).unwrap();
+            assert_eq!(trace.ssrc(), Some(4));
+        }
+    }
+
+    #[test]
+    fn rejects_a_truncated_capture() {
+        let contents = capture(&[(7, 0, 0, 0)]);
+        assert!(NetworkTrace::from_pcap(&contents[..contents.len() - 1]).is_err());
+    }
+}